@@ -8,16 +8,24 @@ fn main() {
 
     // Start the library's server in a separate thread
     thread::spawn(move || {
-        if let Err(err) = command_server::field_commands_forever(sender) {
+        if let Err(err) = command_server::field_commands_forever(
+            sender,
+            Default::default(),
+            std::sync::Arc::new(command_server::events::EventHub::new(10.0)),
+            command_server::ServerConfig::default(),
+            Default::default(),
+            command_server::StopSignal::default(),
+        ) {
             eprintln!("Error in library: {:?}", err);
         }
     });
 
     // Main thread can now handle received commands or perform other tasks
     // For demonstration, let's just print received commands
-    for received_cmd in receiver {
-        println!("Received command: {:?}", received_cmd);
+    for command_server::Envelope { command, reply } in receiver {
+        println!("Received command: {:?}", command);
         // Implement your logic to handle the received commands here
+        let _ = reply.send(Ok(serde_json::Value::Null));
     }
 
     // Optionally, you can perform cleanup or other tasks before exiting
@@ -0,0 +1,272 @@
+use crate::http::Request;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Server settings from the `server` section of config.json.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ServerConfig {
+    /// Where to serve, e.g. "0.0.0.0:5001", "[::]:5001" or "127.0.0.1:5001"
+    #[serde(default = "default_listen")]
+    pub listen: Vec<SocketAddr>,
+    /// Only accept connections from this machine, whatever `listen` says
+    #[serde(default)]
+    pub localhost_only: bool,
+    /// Required for every command. Without it the server is open to anyone, as before.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Allows the read-only routes (state, listings and the event stream)
+    #[serde(default)]
+    pub read_token: Option<String>,
+    /// Also take commands as JSON lines on this Unix socket, see `socket`. Left out it
+    /// is `socket::default_path()`, null turns the socket off.
+    #[serde(default = "default_socket_path")]
+    pub socket_path: Option<String>,
+}
+
+fn default_listen() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([0, 0, 0, 0], 5001))]
+}
+
+fn default_socket_path() -> Option<String> {
+    Some(crate::socket::default_path())
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: default_listen(),
+            localhost_only: false,
+            admin_token: None,
+            read_token: None,
+            socket_path: default_socket_path(),
+        }
+    }
+}
+
+// Config gets dumped to the log at startup, tokens must not be
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |token: &Option<String>| token.as_ref().map(|_| "<redacted>");
+        f.debug_struct("ServerConfig")
+            .field("listen", &self.listen)
+            .field("localhost_only", &self.localhost_only)
+            .field("admin_token", &redact(&self.admin_token))
+            .field("read_token", &redact(&self.read_token))
+            .field("socket_path", &self.socket_path)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Public,
+    ReadOnly,
+    Admin,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Rejection {
+    #[error("missing or invalid token")]
+    Unauthenticated,
+    #[error("token does not allow this")]
+    Forbidden,
+}
+
+impl Rejection {
+    pub fn status(&self) -> u16 {
+        match self {
+            Rejection::Unauthenticated => 401,
+            Rejection::Forbidden => 403,
+        }
+    }
+}
+
+/// GETs only read, everything else changes something. The console page itself holds
+/// no secrets, it asks for the token it needs.
+pub fn required_scope(request: &Request) -> Scope {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => Scope::Public,
+        ("GET", _) => Scope::ReadOnly,
+        _ => Scope::Admin,
+    }
+}
+
+/// Compares without stopping at the first difference, so timing says nothing about
+/// how much of a guess was right.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The token from `Authorization: Bearer`, `X-Mjoy-Token` or `?token=`. Browsers
+/// can't set headers on an EventSource, hence the query parameter.
+fn given_token(request: &Request) -> Option<String> {
+    if let Some(bearer) = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some(bearer.trim().to_string());
+    }
+    request
+        .header("x-mjoy-token")
+        .map(|token| token.to_string())
+        .or_else(|| request.query_param("token"))
+}
+
+impl ServerConfig {
+    pub fn scope(&self, request: &Request) -> Scope {
+        let Some(admin_token) = self.admin_token.as_deref() else {
+            return Scope::Admin;
+        };
+        match given_token(request).as_deref() {
+            Some(token) if same_token(token, admin_token) => Scope::Admin,
+            Some(token)
+                if self
+                    .read_token
+                    .as_deref()
+                    .is_some_and(|read_token| same_token(token, read_token)) =>
+            {
+                Scope::ReadOnly
+            }
+            _ => Scope::Public,
+        }
+    }
+
+    pub fn authorize(&self, request: &Request) -> Result<(), Rejection> {
+        let required = required_scope(request);
+        let granted = self.scope(request);
+        if granted >= required {
+            Ok(())
+        } else if granted == Scope::Public {
+            Err(Rejection::Unauthenticated)
+        } else {
+            Err(Rejection::Forbidden)
+        }
+    }
+
+    /// `localhost_only` moves an address to loopback, keeping its port and IP version.
+    pub fn local_address(&self, address: SocketAddr) -> SocketAddr {
+        match (self.localhost_only, address.ip()) {
+            (true, IpAddr::V4(_)) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+            (true, IpAddr::V6(_)) => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port()),
+            (false, _) => address,
+        }
+    }
+
+    /// `listen` without repeats, each through `local_address`.
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = Vec::new();
+        for address in self.listen.iter() {
+            let address = self.local_address(*address);
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", method, target);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            admin_token: Some("s3cret".to_string()),
+            read_token: Some("peek".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn open_without_tokens() {
+        let config = ServerConfig::default();
+        assert_eq!(config.authorize(&request("POST", "/setup", &[])), Ok(()));
+        assert_eq!(
+            config.listen_addresses(),
+            vec!["0.0.0.0:5001".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn localhost_only_moves_listeners_to_loopback() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"listen": ["0.0.0.0:5001", "[::]:5001", "127.0.0.1:5001", "10.0.0.2:8080"],
+                "localhost_only": true}"#,
+        )
+        .unwrap();
+        let expected: Vec<SocketAddr> = ["127.0.0.1:5001", "[::1]:5001", "127.0.0.1:8080"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(config.listen_addresses(), expected);
+    }
+
+    #[test]
+    fn debug_hides_tokens() {
+        let shown = format!("{:?}", config());
+        assert!(!shown.contains("s3cret"));
+        assert!(!shown.contains("peek"));
+    }
+
+    #[test]
+    fn admin_token_from_header_or_query() {
+        let config = config();
+        assert_eq!(
+            config.authorize(&request("POST", "/setup", &[])),
+            Err(Rejection::Unauthenticated)
+        );
+        assert_eq!(
+            config.authorize(&request(
+                "POST",
+                "/setup",
+                &[("Authorization", "Bearer s3cret")]
+            )),
+            Ok(())
+        );
+        assert_eq!(
+            config.authorize(&request("POST", "/setup", &[("X-Mjoy-Token", "s3cret")])),
+            Ok(())
+        );
+        assert_eq!(
+            config.authorize(&request("POST", "/setup?token=s3cret", &[])),
+            Ok(())
+        );
+        assert_eq!(
+            config.authorize(&request("POST", "/setup?token=s3cre", &[])),
+            Err(Rejection::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn read_token_only_reads() {
+        let config = config();
+        assert_eq!(
+            config.authorize(&request("GET", "/events?token=peek", &[])),
+            Ok(())
+        );
+        assert_eq!(
+            config.authorize(&request("POST", "/start?token=peek", &[])),
+            Err(Rejection::Forbidden)
+        );
+        assert_eq!(
+            config.authorize(&request("GET", "/state", &[])),
+            Err(Rejection::Unauthenticated)
+        );
+        // The console page loads without a token
+        assert_eq!(config.authorize(&request("GET", "/", &[])), Ok(()));
+    }
+}
@@ -0,0 +1,162 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Messages a slow client may fall behind by before it is dropped
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+/// Fans main's state out to `/events` clients as Server-Sent Events. Main offers the
+/// latest value of every stream each tick; only changed ones are sent, and no more
+/// often than the configured rate.
+pub struct EventHub {
+    interval: Duration,
+    inner: Mutex<HubState>,
+}
+
+#[derive(Default)]
+struct HubState {
+    subscribers: Vec<SyncSender<String>>,
+    last_sent: BTreeMap<String, Value>,
+    last_flush: Option<Instant>,
+}
+
+/// One event in `text/event-stream` framing.
+pub fn sse_message(kind: &str, data: &Value) -> String {
+    format!("event: {}\ndata: {}\n\n", kind, data)
+}
+
+impl EventHub {
+    /// A rate of zero or less sends every change as soon as it is offered.
+    pub fn new(max_rate_hz: f32) -> Self {
+        let interval = if max_rate_hz > 0.0 {
+            Duration::from_secs_f32(1.0 / max_rate_hz)
+        } else {
+            Duration::ZERO
+        };
+        EventHub {
+            interval,
+            inner: Mutex::new(HubState::default()),
+        }
+    }
+
+    /// New clients start with the current value of every stream.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_BACKLOG);
+        let mut state = self.inner.lock().unwrap();
+        for (kind, data) in state.last_sent.iter() {
+            let _ = tx.try_send(sse_message(kind, data));
+        }
+        state.subscribers.push(tx);
+        rx
+    }
+
+    pub fn offer(&self, now: Instant, updates: Vec<(&str, Value)>) {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(last_flush) = state.last_flush {
+            if now.duration_since(last_flush) < self.interval {
+                return;
+            }
+        }
+        state.last_flush = Some(now);
+
+        for (kind, data) in updates {
+            if state.last_sent.get(kind) == Some(&data) {
+                continue;
+            }
+            let message = sse_message(kind, &data);
+            state
+                .subscribers
+                .retain(|subscriber| match subscriber.try_send(message.clone()) {
+                    Ok(()) => true,
+                    // A missed update would stay stale until that stream changes again.
+                    // Ending the stream makes the browser reconnect and start afresh.
+                    Err(TrySendError::Full(_)) => false,
+                    Err(TrySendError::Disconnected(_)) => false,
+                });
+            state.last_sent.insert(kind.to_string(), data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sends_changes_only() {
+        let hub = EventHub::new(0.0);
+        let rx = hub.subscribe();
+        let now = Instant::now();
+
+        hub.offer(
+            now,
+            vec![("threshold", json!(0.9)), ("game_state", json!("binding"))],
+        );
+        hub.offer(
+            now,
+            vec![
+                ("threshold", json!(0.9)),
+                ("game_state", json!("team_select")),
+            ],
+        );
+
+        let sent: Vec<String> = rx.try_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                "event: threshold\ndata: 0.9\n\n",
+                "event: game_state\ndata: \"binding\"\n\n",
+                "event: game_state\ndata: \"team_select\"\n\n",
+            ]
+        );
+
+        // A late subscriber gets the latest of everything straight away
+        let late = hub.subscribe();
+        assert_eq!(late.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn throttles_to_rate() {
+        let hub = EventHub::new(10.0);
+        let rx = hub.subscribe();
+        let start = Instant::now();
+
+        hub.offer(start, vec![("threshold", json!(0.5))]);
+        hub.offer(
+            start + Duration::from_millis(20),
+            vec![("threshold", json!(0.6))],
+        );
+        hub.offer(
+            start + Duration::from_millis(150),
+            vec![("threshold", json!(0.7))],
+        );
+
+        let sent: Vec<String> = rx.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].contains("0.7"));
+    }
+
+    #[test]
+    fn drops_subscribers_that_fall_behind() {
+        let hub = EventHub::new(0.0);
+        let slow = hub.subscribe();
+        for i in 0..=SUBSCRIBER_BACKLOG {
+            hub.offer(Instant::now(), vec![("threshold", json!(i))]);
+        }
+        assert!(hub.inner.lock().unwrap().subscribers.is_empty());
+        // What was queued still arrives, then the stream ends
+        assert_eq!(slow.try_iter().count(), SUBSCRIBER_BACKLOG);
+        assert!(slow.recv().is_err());
+    }
+
+    #[test]
+    fn drops_disconnected_subscribers() {
+        let hub = EventHub::new(0.0);
+        drop(hub.subscribe());
+        hub.offer(Instant::now(), vec![("threshold", json!(0.5))]);
+        assert!(hub.inner.lock().unwrap().subscribers.is_empty());
+    }
+}
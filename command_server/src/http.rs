@@ -0,0 +1,281 @@
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+/// Bodies are small JSON documents, anything bigger is a mistake or abuse
+const MAX_BODY_LEN: usize = 1 << 20;
+const MAX_HEADER_LINES: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("connection error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed request line {0:?}")]
+    RequestLine(String),
+    #[error("malformed header {0:?}")]
+    Header(String),
+    #[error("too many headers")]
+    TooManyHeaders,
+    #[error("bad Content-Length {0:?}")]
+    ContentLength(String),
+    #[error("body of {0} bytes is too large")]
+    BodyTooLarge(usize),
+}
+
+impl HttpError {
+    /// Status to answer with. `Io` errors leave nothing to answer on.
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::BodyTooLarge(_) => 413,
+            _ => 400,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Everything after the `?`, undecoded
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request: request line, headers, then exactly Content-Length bytes.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, HttpError> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                (method.to_string(), target)
+            }
+            _ => return Err(HttpError::RequestLine(line.trim_end().to_string())),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (path, query) = (path.to_string(), query.to_string());
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADER_LINES {
+                return Err(HttpError::TooManyHeaders);
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| HttpError::Header(line.to_string()))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method,
+            path,
+            query,
+            headers,
+            body: Vec::new(),
+        };
+        if let Some(length) = request.header("content-length") {
+            let length: usize = length
+                .parse()
+                .map_err(|_| HttpError::ContentLength(length.to_string()))?;
+            if length > MAX_BODY_LEN {
+                return Err(HttpError::BodyTooLarge(length));
+            }
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body)?;
+        }
+        Ok(request)
+    }
+
+    /// Header names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// A percent-decoded query parameter.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    pub fn body_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+
+    pub fn json(&self) -> Option<Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+impl Response {
+    pub fn json(status: u16, body: &Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn html(body: &str) -> Self {
+        Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn parse(raw: &str) -> Result<Request, HttpError> {
+        Request::read_from(&mut Cursor::new(raw.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn reads_headers_and_body() {
+        let request = parse(
+            "POST /preset/save?x=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 16\r\n\r\n{\"name\": \"Cup\"}\nEXTRA",
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/preset/save");
+        assert_eq!(request.query_param("x").as_deref(), Some("1"));
+        assert_eq!(request.header("Content-Length"), Some("16"));
+        assert_eq!(request.json().unwrap()["name"], "Cup");
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        let request = parse("GET /events?a=1&token=s%2F3+cr%zz HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.query_param("token").as_deref(), Some("s/3 cr%zz"));
+        assert_eq!(request.query_param("b"), None);
+    }
+
+    #[test]
+    fn no_content_length_means_no_body() {
+        let request = parse("POST /setup HTTP/1.1\r\n\r\nsetup setup").unwrap();
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(matches!(
+            parse("hello\r\n\r\n"),
+            Err(HttpError::RequestLine(_))
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nno colon\r\n\r\n"),
+            Err(HttpError::Header(_))
+        ));
+        let err = parse("POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n").unwrap_err();
+        assert_eq!(err.status(), 413);
+        // Promised more than was sent
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"),
+            Err(HttpError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn writes_status_line_and_length() {
+        let mut out = Vec::new();
+        Response::error(404, "nope").write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.contains("Content-Length: 16\r\n"));
+        assert!(out.ends_with("{\"error\":\"nope\"}"));
+
+        for (status, line) in [(401, "401 Unauthorized"), (403, "403 Forbidden")] {
+            let mut out = Vec::new();
+            Response::error(status, "token").write_to(&mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with(&format!("HTTP/1.1 {}\r\n", line)));
+        }
+    }
+}
@@ -1,109 +1,976 @@
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::{SendError, Sender};
+pub mod auth;
+pub mod events;
+pub mod http;
+pub mod metrics;
+pub mod osc;
+pub mod socket;
+pub mod state;
+
+pub use auth::ServerConfig;
+use events::EventHub;
+use http::{HttpError, Request, Response};
+use metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use state::SharedSnapshot;
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
+
+// Connections waiting for accept, per listener
+const BACKLOG: i32 = 128;
+// How long main gets to answer a query, and a client to send its request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// The control console, a single page that talks to the routes below
+const CONSOLE_HTML: &str = include_str!("../templates/console.html");
 
-// Constants for server configuration
-const HOST: &str = "0.0.0.0";
-const PORT: &str = "5001";
+// Idle event streams get a comment line this often so proxies keep them open
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// What Prometheus expects from a text scrape
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
 
 // Enum to represent different commands
 #[derive(Debug)]
 pub enum Command {
     Setup,
     Start,
-    Teams(usize),
+    /// Back to team select, resized to this many teams if given
+    Teams(Option<usize>),
+    SavePreset(String),
+    LoadPreset(String),
+    ListPresets,
+    RebindOne(String),
+    UndoBinding,
+    Identify,
+    Calibrate,
+    ListUnbound,
+    /// The name pool as JSON
+    ExportNames,
+    ImportNames(String),
+    RetireName(String),
+    RestoreName(String),
+    /// Release every output and stop reading the pads until Resume
+    Pause,
+    Resume,
+    MovePlayer {
+        player: String,
+        team: String,
+    },
+    RenameTeam {
+        team: String,
+        name: String,
+    },
+    SetThreshold(ThresholdProfile),
+    SetAggregationMode(AggregationMode),
+    /// Re-read the config file, for the settings that can change while running
+    ReloadConfig,
+    Shutdown,
+    /// Load the timeline file and run it from the top
+    StartSchedule,
+    PauseSchedule,
+    ResumeSchedule,
 }
 
-pub fn field_commands_forever(sender: Sender<Command>) -> Result<(), SendError<Command>> {
-    // Bind to the host and port
-    let endpoint = format!("{}:{}", HOST, PORT);
-    let listener = TcpListener::bind(endpoint).unwrap();
-    println!("Web server is listening at port {}", PORT);
+impl Command {
+    /// Short name for logs and metric labels.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Setup => "setup",
+            Command::Start => "start",
+            Command::Teams(_) => "teams",
+            Command::SavePreset(_) => "save_preset",
+            Command::LoadPreset(_) => "load_preset",
+            Command::ListPresets => "list_presets",
+            Command::RebindOne(_) => "rebind_one",
+            Command::UndoBinding => "undo_binding",
+            Command::Identify => "identify",
+            Command::Calibrate => "calibrate",
+            Command::ListUnbound => "list_unbound",
+            Command::ExportNames => "export_names",
+            Command::ImportNames(_) => "import_names",
+            Command::RetireName(_) => "retire_name",
+            Command::RestoreName(_) => "restore_name",
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::MovePlayer { .. } => "move_player",
+            Command::RenameTeam { .. } => "rename_team",
+            Command::SetThreshold(_) => "set_threshold",
+            Command::SetAggregationMode(_) => "set_aggregation_mode",
+            Command::ReloadConfig => "reload_config",
+            Command::Shutdown => "shutdown",
+            Command::StartSchedule => "start_schedule",
+            Command::PauseSchedule => "pause_schedule",
+            Command::ResumeSchedule => "resume_schedule",
+        }
+    }
+}
 
-    // Accept incoming connections
-    for incoming_stream in listener.incoming() {
-        let stream = incoming_stream.unwrap();
+/// Why main turned a command down.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Refusal {
+    /// Not possible right now, e.g. Start while binding
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// The command itself makes no sense
+    #[error("{0}")]
+    Invalid(String),
+    /// Main tried and something went wrong, e.g. a file could not be written
+    #[error("{0}")]
+    Failed(String),
+}
 
-        // Spawn a new thread to handle the connection
-        let sender_clone = sender.clone();
-        thread::spawn(move || {
-            if let Err(err) = handle_connection(stream, sender_clone) {
-                eprintln!("Error handling connection: {:?}", err);
+impl Refusal {
+    pub fn status(&self) -> u16 {
+        match self {
+            Refusal::Conflict(_) => 409,
+            Refusal::NotFound(_) => 404,
+            Refusal::Invalid(_) => 400,
+            Refusal::Failed(_) => 500,
+        }
+    }
+}
+
+/// Main's answer: the result of a query, or the state a command left the game in.
+pub type Ack = Result<Value, Refusal>;
+
+/// A command and where main sends its `Ack`.
+#[derive(Debug)]
+pub struct Envelope {
+    pub command: Command,
+    pub reply: Sender<Ack>,
+}
+
+/// Why a threshold profile can't be used, whichever way it came in.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ThresholdError {
+    #[error("a fixed threshold is between 0 and 1, not {0}")]
+    Fixed(f32),
+    #[error("a drift needs a min between 0 and 1 and a max no lower, not {min} to {max}")]
+    Drift { min: f32, max: f32 },
+}
+
+/// How the press threshold behaves during a game.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdProfile {
+    Fixed(f32),
+    /// Jumps somewhere between `min` and `max` every few seconds. Anything above 0.95
+    /// is held at 0.95, so a full team can always press, and a `max` above 1 only
+    /// makes that happen more often.
+    Drift {
+        min: f32,
+        max: f32,
+    },
+}
+
+impl Default for ThresholdProfile {
+    /// The classic chaos, which spends a good while needing nearly everyone
+    fn default() -> Self {
+        ThresholdProfile::Drift {
+            min: 0.49,
+            max: 1.1,
+        }
+    }
+}
+
+impl ThresholdProfile {
+    /// The one check for the HTTP routes, OSC and the timeline.
+    pub fn check(self) -> Result<Self, ThresholdError> {
+        let valid = match self {
+            ThresholdProfile::Fixed(threshold) => (0.0..=1.0).contains(&threshold),
+            ThresholdProfile::Drift { min, max } => {
+                (0.0..=1.0).contains(&min) && min <= max && max.is_finite()
             }
-        });
+        };
+        match self {
+            _ if valid => Ok(self),
+            ThresholdProfile::Fixed(threshold) => Err(ThresholdError::Fixed(threshold)),
+            ThresholdProfile::Drift { min, max } => Err(ThresholdError::Drift { min, max }),
+        }
     }
-    Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream, sender: Sender<Command>) -> Result<(), std::io::Error> {
-    // Buffer to read the incoming request
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer)?;
+/// How the players' presses on a team become the team's press.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregationMode {
+    /// More of the team than the threshold has to agree
+    #[default]
+    Threshold,
+    /// One player is enough
+    Any,
+    /// Everyone has to agree
+    All,
+}
+
+/// Every method and path the server answers, for telling 404 from 405.
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/"),
+    ("POST", "/setup"),
+    ("POST", "/start"),
+    ("POST", "/team"),
+    ("POST", "/teams"),
+    ("POST", "/preset/save"),
+    ("POST", "/preset/load"),
+    ("GET", "/presets"),
+    ("POST", "/binding/rebind"),
+    ("POST", "/binding/undo"),
+    ("GET", "/binding/unbound"),
+    ("POST", "/identify"),
+    ("POST", "/calibrate"),
+    ("GET", "/names"),
+    ("POST", "/names/import"),
+    ("POST", "/names/retire"),
+    ("POST", "/names/restore"),
+    ("POST", "/pause"),
+    ("POST", "/resume"),
+    ("POST", "/team/move"),
+    ("POST", "/team/rename"),
+    ("POST", "/threshold"),
+    ("POST", "/aggregation"),
+    ("POST", "/config/reload"),
+    ("POST", "/shutdown"),
+    ("POST", "/schedule/start"),
+    ("POST", "/schedule/pause"),
+    ("POST", "/schedule/resume"),
+    ("GET", "/state"),
+    ("GET", "/state/game"),
+    ("GET", "/state/teams"),
+    ("GET", "/state/bindings"),
+    ("GET", "/state/devices"),
+    ("GET", "/state/schedule"),
+    ("GET", "/events"),
+    ("GET", "/metrics"),
+];
+
+#[derive(Default)]
+struct StopState {
+    stopped: AtomicBool,
+    /// One address per listener to connect to
+    wake: Mutex<Vec<SocketAddr>>,
+    /// The control socket, woken the same way
+    wake_socket: Mutex<Option<PathBuf>>,
+    /// Requests being answered, which stopping waits for
+    answering: Mutex<usize>,
+    answered: Condvar,
+}
+
+/// Tells the listeners and the control socket to stop accepting. `accept` blocks, so
+/// stopping also pokes each of them with a connection of its own to wake it.
+///
+/// Stopping then waits for the answers already on their way, so the reply to
+/// `/shutdown` is written before main returns and the process exits.
+#[derive(Clone, Default)]
+pub struct StopSignal(Arc<StopState>);
+
+impl StopSignal {
+    pub fn stop(&self) {
+        self.0.stopped.store(true, Ordering::SeqCst);
+        if let Ok(wake) = self.0.wake.lock() {
+            for address in wake.iter() {
+                let _ = TcpStream::connect_timeout(address, READ_TIMEOUT);
+            }
+        }
+        if let Ok(wake_socket) = self.0.wake_socket.lock() {
+            if let Some(path) = wake_socket.as_ref() {
+                let _ = UnixStream::connect(path);
+            }
+        }
+        if let Ok(answering) = self.0.answering.lock() {
+            let _ = self
+                .0
+                .answered
+                .wait_timeout_while(answering, READ_TIMEOUT, |answering| *answering > 0);
+        }
+    }
+
+    fn wake_socket_on(&self, path: &str) {
+        if let Ok(mut wake_socket) = self.0.wake_socket.lock() {
+            *wake_socket = Some(PathBuf::from(path));
+        }
+    }
+
+    /// Held while a request is answered, until its response is written.
+    fn answering(&self) -> Answering {
+        if let Ok(mut answering) = self.0.answering.lock() {
+            *answering += 1;
+        }
+        Answering(self.0.clone())
+    }
 
-    // Convert the request buffer to a string
-    let request_str = String::from_utf8_lossy(&buffer);
-    println!("Request: {}", request_str);
+    pub fn is_stopped(&self) -> bool {
+        self.0.stopped.load(Ordering::SeqCst)
+    }
 
-    // Parse the request to determine the command
-    let command = parse_command(&request_str);
-    match command {
-        Some(cmd) => {
-            // Send the command through the channel
-            sender.send(cmd).unwrap();
+    /// A listener on 0.0.0.0 or [::] is woken through loopback.
+    fn wake_on(&self, listening: SocketAddr) {
+        let ip = match listening.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+            ip => ip,
+        };
+        if let Ok(mut wake) = self.0.wake.lock() {
+            wake.push(SocketAddr::new(ip, listening.port()));
         }
-        None => {
-            eprintln!("Invalid command received");
+    }
+}
+
+struct Answering(Arc<StopState>);
+
+impl Drop for Answering {
+    fn drop(&mut self) {
+        if let Ok(mut answering) = self.0.answering.lock() {
+            *answering -= 1;
         }
+        self.0.answered.notify_all();
     }
+}
 
-    let response = "HTTP/1.1 200 OK\n";
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("no listen addresses in the server config")]
+    NoListeners,
+    #[error("failed to listen on {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: std::io::Error,
+    },
+}
+
+/// Like `TcpListener::bind`, but [::] only takes IPv6, so it can sit beside a
+/// 0.0.0.0 listener on the same port.
+fn bind(address: SocketAddr) -> Result<TcpListener, std::io::Error> {
+    use socket2::{Domain, Socket, Type};
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
 
-    // Send the response back to the client
-    stream.write(response.as_bytes())?;
-    stream.flush()?;
+/// What every connection thread needs.
+#[derive(Clone)]
+struct Shared {
+    sender: Sender<Envelope>,
+    snapshot: SharedSnapshot,
+    events: Arc<EventHub>,
+    config: Arc<ServerConfig>,
+    metrics: Arc<Metrics>,
+    stop: StopSignal,
+}
+
+/// Serves until `stop`. Every address is bound before anything is served, so a
+/// taken port fails the whole server instead of leaving half of it up. The control
+/// socket is `field_socket_forever`'s, so it stays up either way.
+pub fn field_commands_forever(
+    sender: Sender<Envelope>,
+    snapshot: SharedSnapshot,
+    events: Arc<EventHub>,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+    stop: StopSignal,
+) -> Result<(), ServerError> {
+    let addresses = config.listen_addresses();
+    if addresses.is_empty() {
+        return Err(ServerError::NoListeners);
+    }
+    let mut listeners = Vec::new();
+    for address in addresses {
+        let listener = bind(address)
+            .and_then(|listener| Ok((listener.local_addr()?, listener)))
+            .map_err(|source| ServerError::Bind { address, source })?;
+        listeners.push(listener);
+    }
+    if config.admin_token.is_none() {
+        tracing::warn!("No admin_token set, anyone who can reach the server can control it");
+    }
+
+    let shared = Shared {
+        sender,
+        snapshot,
+        events,
+        config: Arc::new(config),
+        metrics,
+        stop: stop.clone(),
+    };
+
+    thread::scope(|scope| {
+        for (address, listener) in listeners {
+            stop.wake_on(address);
+            tracing::info!("Web server is listening at {}", address);
+            let (shared, stop) = (&shared, &stop);
+            scope.spawn(move || accept_forever(listener, shared, stop));
+        }
+    });
+    tracing::info!("Web server stopped");
     Ok(())
 }
 
-// Function to parse the command from the request string
-fn parse_command(request_str: &str) -> Option<Command> {
-    // Implement your parsing logic here
-    // For simplicity, let's assume the command is extracted from the request
-    // and construct a Command enum accordingly.
-    // In a real application, you would parse the request according to your protocol.
-    // This is just a placeholder.
-    if request_str.contains("setup") {
-        Some(Command::Setup)
-    } else if request_str.contains("start") {
-        Some(Command::Start)
-    } else if let Some(teams_count) = extract_teams_count(request_str) {
-        Some(Command::Teams(teams_count))
+/// Serves the control socket at `config.socket_path` until `stop`, if there is one.
+pub fn field_socket_forever(
+    sender: Sender<Envelope>,
+    snapshot: SharedSnapshot,
+    events: Arc<EventHub>,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+    stop: StopSignal,
+) -> Result<(), std::io::Error> {
+    let Some(path) = config.socket_path.clone() else {
+        return Ok(());
+    };
+    let shared = Shared {
+        sender,
+        snapshot,
+        events,
+        config: Arc::new(config),
+        metrics,
+        stop,
+    };
+    socket::serve(&path, shared)
+}
+
+/// Hands each connection to a thread of its own until stopped.
+fn accept_forever(listener: TcpListener, shared: &Shared, stop: &StopSignal) {
+    for incoming_stream in listener.incoming() {
+        if stop.is_stopped() {
+            break;
+        }
+        let stream = match incoming_stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, shared) {
+                tracing::warn!("Error handling connection: {:?}", err);
+            }
+        });
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: Shared) -> Result<(), std::io::Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let answer = |response: Response| {
+        shared.metrics.inc(
+            "mjoy_http_requests_total",
+            &[("status", &response.status.to_string())],
+        );
+        response.write_to(&mut &stream)
+    };
+
+    let request = match Request::read_from(&mut reader) {
+        Ok(request) => request,
+        Err(HttpError::Io(err)) => return Err(err),
+        Err(err) => {
+            tracing::warn!("Bad request: {}", err);
+            return answer(Response::error(err.status(), &err.to_string()));
+        }
+    };
+
+    if let Err(rejection) = shared.config.authorize(&request) {
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+        tracing::warn!(
+            "Rejected {} {} from {}: {}",
+            request.method,
+            request.path,
+            peer,
+            rejection
+        );
+        return answer(Response::error(rejection.status(), &rejection.to_string()));
+    }
+
+    if request.method == "GET" && request.path == "/events" {
+        tracing::debug!("Request: GET /events -> stream");
+        shared
+            .metrics
+            .inc("mjoy_http_requests_total", &[("status", "200")]);
+        return stream_events(&stream, &shared.events);
+    }
+
+    let _answering = shared.stop.answering();
+    let response = respond(&request, &shared);
+    tracing::debug!(
+        "Request: {} {} -> {}",
+        request.method,
+        request.path,
+        response.status
+    );
+    answer(response)
+}
+
+/// Everything but the event stream, for the web server and the control socket.
+fn respond(request: &Request, shared: &Shared) -> Response {
+    if request.method == "GET" && request.path == "/metrics" {
+        Response {
+            status: 200,
+            content_type: METRICS_CONTENT_TYPE,
+            body: shared.metrics.render(),
+        }
     } else {
-        None
+        route(request, &shared.sender, &shared.snapshot)
     }
 }
 
-use serde_json::Value;
+/// Holds the connection open and forwards events until the client goes away.
+fn stream_events(mut stream: &TcpStream, events: &EventHub) -> Result<(), std::io::Error> {
+    let rx = events.subscribe();
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+    )?;
+    loop {
+        let message = match rx.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+/// Hands a command to main and waits for its `Ack`, which becomes the response.
+fn send(sender: &Sender<Envelope>, command: Command) -> Response {
+    let (reply, acks) = channel();
+    if sender.send(Envelope { command, reply }).is_err() {
+        return Response::error(503, "game loop is not running");
+    }
+    match acks.recv_timeout(REPLY_TIMEOUT) {
+        Ok(Ok(answer)) => Response::json(200, &answer),
+        Ok(Err(refusal)) => Response::error(refusal.status(), &refusal.to_string()),
+        Err(_) => Response::error(504, "game loop did not answer"),
+    }
+}
 
-// Function to extract the number of teams from the request string
-fn extract_teams_count(request_str: &str) -> Option<usize> {
-    // Find the start and end positions of the JSON object within curly braces
-    let start_pos = request_str.find('{')?;
-    let end_pos = request_str.rfind('}')?;
-
-    // Extract the JSON string
-    let json_str = &request_str[start_pos..=end_pos];
-
-    // Parse the JSON string
-    if let Ok(json_value) = serde_json::from_str::<Value>(json_str) {
-        // Check if the JSON object contains the "teams" field
-        if let Some(teams_value) = json_value.get("teams") {
-            // Try to parse the value of "teams" as usize
-            if let Some(teams_count) = teams_value.as_u64() {
-                return Some(teams_count as usize);
+/// A string field from a JSON object body.
+fn string_field(request: &Request, field: &str) -> Result<String, Response> {
+    request
+        .json()
+        .and_then(|body| Some(body.get(field)?.as_str()?.to_string()))
+        .ok_or_else(|| {
+            Response::error(
+                400,
+                &format!("expected a JSON body with a \"{}\" string", field),
+            )
+        })
+}
+
+/// The `name` string from a `{"name": ...}` body.
+fn name_field(request: &Request) -> Result<String, Response> {
+    string_field(request, "name")
+}
+
+/// `{"threshold": 0.7}` pins it, `{"min": 0.3, "max": 0.6}` drifts in that range and
+/// `{"threshold": null}` goes back to the classic drift.
+fn threshold_field(request: &Request) -> Result<ThresholdProfile, Response> {
+    let invalid = || {
+        Response::error(
+            400,
+            "expected a JSON body with a \"threshold\" number or null, or a \"min\" and \"max\"",
+        )
+    };
+    let body = request.json().ok_or_else(invalid)?;
+    let number = |field: &str| {
+        body.get(field)
+            .and_then(Value::as_f64)
+            .map(|value| value as f32)
+    };
+    let profile = match (body.get("threshold"), number("min"), number("max")) {
+        (Some(Value::Null), _, _) => ThresholdProfile::default(),
+        (Some(_), _, _) => ThresholdProfile::Fixed(number("threshold").ok_or_else(invalid)?),
+        (None, Some(min), Some(max)) => ThresholdProfile::Drift { min, max },
+        _ => return Err(invalid()),
+    };
+    profile
+        .check()
+        .map_err(|err| Response::error(400, &err.to_string()))
+}
+
+fn aggregation_field(request: &Request) -> Result<AggregationMode, Response> {
+    request
+        .json()
+        .and_then(|body| serde_json::from_value(body.get("mode")?.clone()).ok())
+        .ok_or_else(|| {
+            Response::error(
+                400,
+                "expected a JSON body with a \"mode\" of threshold, any or all",
+            )
+        })
+}
+
+fn with_name(
+    request: &Request,
+    sender: &Sender<Envelope>,
+    command: impl FnOnce(String) -> Command,
+) -> Response {
+    match name_field(request) {
+        Ok(name) => send(sender, command(name)),
+        Err(response) => response,
+    }
+}
+
+/// Answers a `/state` query straight from the last published snapshot.
+fn state(snapshot: &SharedSnapshot, part: impl FnOnce(&state::Snapshot) -> Value) -> Response {
+    match snapshot.read() {
+        Ok(snapshot) => Response::json(200, &part(&snapshot)),
+        Err(_) => Response::error(500, "state snapshot is unavailable"),
+    }
+}
+
+fn route(request: &Request, sender: &Sender<Envelope>, snapshot: &SharedSnapshot) -> Response {
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => Ok(Response::html(CONSOLE_HTML)),
+        ("POST", "/setup") => Ok(send(sender, Command::Setup)),
+        ("POST", "/start") => Ok(send(sender, Command::Start)),
+        ("POST", "/team" | "/teams") => {
+            match request.json().and_then(|body| body.get("teams")?.as_u64()) {
+                Some(teams) => Ok(send(sender, Command::Teams(Some(teams as usize)))),
+                None => Err(Response::error(
+                    400,
+                    "expected a JSON body with a \"teams\" number",
+                )),
             }
         }
+        ("POST", "/preset/save") => Ok(with_name(request, sender, Command::SavePreset)),
+        ("POST", "/preset/load") => Ok(with_name(request, sender, Command::LoadPreset)),
+        ("GET", "/presets") => Ok(send(sender, Command::ListPresets)),
+        ("POST", "/binding/rebind") => Ok(with_name(request, sender, Command::RebindOne)),
+        ("POST", "/binding/undo") => Ok(send(sender, Command::UndoBinding)),
+        ("GET", "/binding/unbound") => Ok(send(sender, Command::ListUnbound)),
+        ("POST", "/identify") => Ok(send(sender, Command::Identify)),
+        ("POST", "/calibrate") => Ok(send(sender, Command::Calibrate)),
+        ("GET", "/names") => Ok(send(sender, Command::ExportNames)),
+        ("POST", "/names/import") => match request.json() {
+            Some(pool @ Value::Array(_)) => {
+                Ok(send(sender, Command::ImportNames(pool.to_string())))
+            }
+            _ => Err(Response::error(400, "expected a JSON array of names")),
+        },
+        ("POST", "/names/retire") => Ok(with_name(request, sender, Command::RetireName)),
+        ("POST", "/names/restore") => Ok(with_name(request, sender, Command::RestoreName)),
+        ("POST", "/pause") => Ok(send(sender, Command::Pause)),
+        ("POST", "/resume") => Ok(send(sender, Command::Resume)),
+        ("POST", "/team/move") => {
+            match (
+                string_field(request, "player"),
+                string_field(request, "team"),
+            ) {
+                (Ok(player), Ok(team)) => Ok(send(sender, Command::MovePlayer { player, team })),
+                (Err(response), _) | (_, Err(response)) => Err(response),
+            }
+        }
+        ("POST", "/team/rename") => match (string_field(request, "team"), name_field(request)) {
+            (Ok(team), Ok(name)) => Ok(send(sender, Command::RenameTeam { team, name })),
+            (Err(response), _) | (_, Err(response)) => Err(response),
+        },
+        ("POST", "/threshold") => {
+            threshold_field(request).map(|threshold| send(sender, Command::SetThreshold(threshold)))
+        }
+        ("POST", "/aggregation") => {
+            aggregation_field(request).map(|mode| send(sender, Command::SetAggregationMode(mode)))
+        }
+        ("POST", "/config/reload") => Ok(send(sender, Command::ReloadConfig)),
+        ("POST", "/shutdown") => Ok(send(sender, Command::Shutdown)),
+        ("POST", "/schedule/start") => Ok(send(sender, Command::StartSchedule)),
+        ("POST", "/schedule/pause") => Ok(send(sender, Command::PauseSchedule)),
+        ("POST", "/schedule/resume") => Ok(send(sender, Command::ResumeSchedule)),
+        ("GET", "/state") => Ok(state(snapshot, |s| s.to_json())),
+        ("GET", "/state/game") => Ok(state(snapshot, |s| s.game())),
+        ("GET", "/state/teams") => Ok(state(snapshot, |s| s.teams.clone())),
+        ("GET", "/state/bindings") => Ok(state(snapshot, |s| s.bindings.clone())),
+        ("GET", "/state/devices") => Ok(state(snapshot, |s| s.devices.clone())),
+        ("GET", "/state/schedule") => Ok(state(snapshot, |s| s.schedule.clone())),
+        (_, path) if ROUTES.iter().any(|(_, route)| *route == path) => Err(Response::error(
+            405,
+            &format!("{} is not allowed on {}", request.method, path),
+        )),
+        (_, path) => Err(Response::error(404, &format!("unknown command {}", path))),
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /// Stands in for main: accepts everything and passes on what it was sent.
+    fn fake_main() -> (Sender<Envelope>, std::sync::mpsc::Receiver<Command>) {
+        let (tx, envelopes) = channel::<Envelope>();
+        let (seen, rx) = channel();
+        thread::spawn(move || {
+            for Envelope { command, reply } in envelopes {
+                let _ = seen.send(command);
+                let _ = reply.send(Ok(json!({ "game_state": "team_select" })));
+            }
+        });
+        (tx, rx)
+    }
+
+    #[test]
+    fn routes_by_path_only() {
+        let (tx, rx) = fake_main();
+        let snapshot = SharedSnapshot::default();
+        assert_eq!(
+            route(&request("POST", "/setup", ""), &tx, &snapshot).status,
+            200
+        );
+        assert!(matches!(rx.try_recv(), Ok(Command::Setup)));
+
+        // Used to trigger Setup because the request mentioned "setup"
+        let response = route(
+            &request("POST", "/preset/save", r#"{"name": "setup"}"#),
+            &tx,
+            &snapshot,
+        );
+        assert_eq!(response.status, 200);
+        assert!(matches!(rx.try_recv(), Ok(Command::SavePreset(name)) if name == "setup"));
+
+        let console = route(&request("GET", "/", ""), &tx, &snapshot);
+        assert_eq!(console.content_type, "text/html; charset=utf-8");
+        assert!(console.body.contains("<title>Control Console</title>"));
+
+        assert_eq!(
+            route(&request("POST", "/restart", ""), &tx, &snapshot).status,
+            404
+        );
+        assert_eq!(
+            route(&request("GET", "/setup", ""), &tx, &snapshot).status,
+            405
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn bad_bodies_are_rejected() {
+        let (tx, rx) = fake_main();
+        let snapshot = SharedSnapshot::default();
+        assert_eq!(
+            route(&request("POST", "/team", "{}"), &tx, &snapshot).status,
+            400
+        );
+        assert_eq!(
+            route(&request("POST", "/preset/load", "Cup"), &tx, &snapshot).status,
+            400
+        );
+        assert_eq!(
+            route(
+                &request("POST", "/names/import", r#"{"name": "Egg"}"#),
+                &tx,
+                &snapshot
+            )
+            .status,
+            400
+        );
+        assert_eq!(
+            route(&request("POST", "/team", r#"{"teams": 3}"#), &tx, &snapshot).status,
+            200
+        );
+        assert!(matches!(rx.try_recv(), Ok(Command::Teams(Some(3)))));
+    }
+
+    #[test]
+    fn live_control_commands() {
+        let (tx, rx) = fake_main();
+        let snapshot = SharedSnapshot::default();
+
+        let response = route(
+            &request(
+                "POST",
+                "/team/move",
+                r#"{"player": "Egg", "team": "Orange Dragons"}"#,
+            ),
+            &tx,
+            &snapshot,
+        );
+        assert_eq!(response.status, 200);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::MovePlayer { player, team }) if player == "Egg" && team == "Orange Dragons"
+        ));
+        assert_eq!(
+            route(
+                &request("POST", "/team/move", r#"{"player": "Egg"}"#),
+                &tx,
+                &snapshot
+            )
+            .status,
+            400
+        );
+
+        route(
+            &request("POST", "/threshold", r#"{"threshold": 0.5}"#),
+            &tx,
+            &snapshot,
+        );
+        assert!(
+            matches!(rx.try_recv(), Ok(Command::SetThreshold(ThresholdProfile::Fixed(t))) if t == 0.5
+            )
+        );
+        route(
+            &request("POST", "/threshold", r#"{"threshold": null}"#),
+            &tx,
+            &snapshot,
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::SetThreshold(profile)) if profile == ThresholdProfile::default()
+        ));
+        route(
+            &request("POST", "/threshold", r#"{"min": 0.3, "max": 0.6}"#),
+            &tx,
+            &snapshot,
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::SetThreshold(ThresholdProfile::Drift { min, max })) if min == 0.3 && max == 0.6
+        ));
+        // The default's own range can be asked for again
+        route(
+            &request("POST", "/threshold", r#"{"min": 0.49, "max": 1.1}"#),
+            &tx,
+            &snapshot,
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::SetThreshold(profile)) if profile == ThresholdProfile::default()
+        ));
+        assert_eq!(
+            route(
+                &request("POST", "/threshold", r#"{"min": 0.6, "max": 0.3}"#),
+                &tx,
+                &snapshot
+            )
+            .status,
+            400
+        );
+        assert_eq!(
+            route(
+                &request("POST", "/threshold", r#"{"threshold": 2}"#),
+                &tx,
+                &snapshot
+            )
+            .status,
+            400
+        );
+
+        route(
+            &request("POST", "/aggregation", r#"{"mode": "any"}"#),
+            &tx,
+            &snapshot,
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Command::SetAggregationMode(AggregationMode::Any))
+        ));
+        assert_eq!(
+            route(
+                &request("POST", "/aggregation", r#"{"mode": "most"}"#),
+                &tx,
+                &snapshot
+            )
+            .status,
+            400
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn stopping_waits_for_answers() {
+        let stop = StopSignal::default();
+        let answering = stop.answering();
+        let started = std::time::Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(answering);
+        });
+        stop.stop();
+        assert!(stop.is_stopped());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn answers_come_from_main() {
+        let (tx, rx) = channel();
+        let snapshot = SharedSnapshot::default();
+        let main = thread::spawn(move || {
+            for Envelope { command, reply } in rx.iter().take(2) {
+                let ack = match command {
+                    Command::ListPresets => Ok(json!(["office-league"])),
+                    _ => Err(Refusal::Conflict("binding is in progress".to_string())),
+                };
+                reply.send(ack).unwrap();
+            }
+        });
+        let response = route(&request("GET", "/presets", ""), &tx, &snapshot);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, r#"["office-league"]"#);
+
+        // Main's refusal comes back as the response
+        let response = route(&request("POST", "/start", ""), &tx, &snapshot);
+        assert_eq!(response.status, 409);
+        assert_eq!(response.body, r#"{"error":"binding is in progress"}"#);
+        main.join().unwrap();
+
+        // Main has gone away
+        assert_eq!(
+            route(&request("GET", "/presets", ""), &tx, &snapshot).status,
+            503
+        );
     }
 
-    None
+    #[test]
+    fn state_comes_from_the_snapshot() {
+        let (tx, rx) = channel();
+        let snapshot = SharedSnapshot::default();
+        {
+            let mut published = snapshot.write().unwrap();
+            published.game_state = json!("team_select");
+            published.threshold = 0.9;
+            published.teams = json!([{"name": "Lucky Bulldogs", "players": []}]);
+        }
+
+        let response = route(&request("GET", "/state/game", ""), &tx, &snapshot);
+        assert_eq!(response.status, 200);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["game_state"], "team_select");
+
+        let response = route(&request("GET", "/state", ""), &tx, &snapshot);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["teams"][0]["name"], "Lucky Bulldogs");
+        assert_eq!(
+            route(&request("POST", "/state", ""), &tx, &snapshot).status,
+            405
+        );
+        // Read straight from the snapshot, main is never asked
+        assert!(rx.try_recv().is_err());
+    }
 }
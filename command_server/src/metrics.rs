@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    name: &'static str,
+    kind: Kind,
+    /// Families without labels show 0 before they are first touched, so graphs
+    /// start at the beginning
+    labelled: bool,
+    help: &'static str,
+}
+
+/// Every metric `/metrics` shows.
+const FAMILIES: &[Family] = &[
+    Family {
+        name: "mjoy_loop_iterations_total",
+        kind: Kind::Counter,
+        labelled: false,
+        help: "Main loop iterations",
+    },
+    Family {
+        name: "mjoy_loop_rate_hz",
+        kind: Kind::Gauge,
+        labelled: false,
+        help: "Main loop iterations per second, over the last second",
+    },
+    Family {
+        name: "mjoy_team_presses_total",
+        kind: Kind::Counter,
+        labelled: true,
+        help: "Buttons pressed and stick directions pushed on each team's output pad",
+    },
+    Family {
+        name: "mjoy_team_inputs_held",
+        kind: Kind::Gauge,
+        labelled: true,
+        help: "Buttons and stick directions held on each team's output pad",
+    },
+    Family {
+        name: "mjoy_devices_connected",
+        kind: Kind::Gauge,
+        labelled: false,
+        help: "Gamepads gilrs can see",
+    },
+    Family {
+        name: "mjoy_device_events_total",
+        kind: Kind::Counter,
+        labelled: true,
+        help: "Gamepads plugged in and unplugged",
+    },
+    Family {
+        name: "mjoy_repaths_total",
+        kind: Kind::Counter,
+        labelled: true,
+        help: "Controller discovery passes, by result",
+    },
+    Family {
+        name: "mjoy_commands_total",
+        kind: Kind::Counter,
+        labelled: true,
+        help: "Commands handled by the main loop, by source and outcome",
+    },
+    Family {
+        name: "mjoy_http_requests_total",
+        kind: Kind::Counter,
+        labelled: true,
+        help: "Requests answered by the command server, by status",
+    },
+];
+
+/// Counters and gauges shared between the main loop and the server, shown on
+/// `/metrics` in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// Family name, then the rendered label set, then the value
+    samples: Mutex<BTreeMap<&'static str, BTreeMap<String, f64>>>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `{team="Orange Dragons",...}`, or nothing without labels.
+fn label_set(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+impl Metrics {
+    fn update(&self, name: &'static str, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+        debug_assert!(
+            FAMILIES.iter().any(|family| family.name == name),
+            "unknown metric {}",
+            name
+        );
+        if let Ok(mut samples) = self.samples.lock() {
+            let value = samples
+                .entry(name)
+                .or_default()
+                .entry(label_set(labels))
+                .or_insert(0.0);
+            f(value);
+        }
+    }
+
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], amount: f64) {
+        self.update(name, labels, |value| *value += amount);
+    }
+
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    pub fn set(&self, name: &'static str, labels: &[(&str, &str)], to: f64) {
+        self.update(name, labels, |value| *value = to);
+    }
+
+    /// Everything in the text exposition format.
+    pub fn render(&self) -> String {
+        let samples = match self.samples.lock() {
+            Ok(samples) => samples,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut text = String::new();
+        for family in FAMILIES {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, kind);
+            match samples.get(family.name) {
+                Some(values) => {
+                    for (labels, value) in values {
+                        let _ = writeln!(text, "{}{} {}", family.name, labels, value);
+                    }
+                }
+                None if !family.labelled => {
+                    let _ = writeln!(text, "{} 0", family.name);
+                }
+                None => (),
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::default();
+        metrics.inc("mjoy_loop_iterations_total", &[]);
+        metrics.inc("mjoy_loop_iterations_total", &[]);
+        metrics.set(
+            "mjoy_team_inputs_held",
+            &[("team", "Orange \"Dragons\"")],
+            3.0,
+        );
+        metrics.inc(
+            "mjoy_commands_total",
+            &[
+                ("command", "start"),
+                ("source", "server"),
+                ("outcome", "ok"),
+            ],
+        );
+
+        let text = metrics.render();
+        assert!(text
+            .contains("# TYPE mjoy_loop_iterations_total counter\nmjoy_loop_iterations_total 2\n"));
+        assert!(text.contains("mjoy_team_inputs_held{team=\"Orange \\\"Dragons\\\"\"} 3\n"));
+        assert!(text.contains(
+            "mjoy_commands_total{command=\"start\",source=\"server\",outcome=\"ok\"} 1\n"
+        ));
+        // Untouched metrics without labels still show up
+        assert!(text.contains("\nmjoy_devices_connected 0\n"));
+        assert!(!text.contains("mjoy_repaths_total 0"));
+    }
+}
@@ -0,0 +1,531 @@
+use crate::{Command, Envelope, ServerConfig, StopSignal, ThresholdProfile, REPLY_TIMEOUT};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+// How often an idle listener looks at the stop signal
+const STOP_POLL: Duration = Duration::from_millis(500);
+// Bigger than any UDP packet
+const MAX_PACKET_LEN: usize = 65536;
+
+/// The `osc` section of config.json. OSC has no tokens, so anyone who can send to the
+/// port, and is in `allow_from` if it is set, can run the mapped commands. The server's
+/// `localhost_only` holds here too.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OscConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// OSC address to command name, see `Command::name`. Replaces the defaults.
+    #[serde(default = "default_addresses")]
+    pub addresses: BTreeMap<String, String>,
+    /// Addresses sent by push buttons, which send 1 when pressed and 0 when let go.
+    /// Only the press runs the command. Anywhere else a lone 0 is just an argument.
+    #[serde(default)]
+    pub buttons: BTreeSet<String>,
+    /// Senders to take packets from, e.g. the lighting desk. Empty takes them from
+    /// anyone.
+    #[serde(default)]
+    pub allow_from: Vec<IpAddr>,
+    /// Listen beyond loopback for anyone even though the server has an `admin_token`,
+    /// which OSC would otherwise get around
+    #[serde(default)]
+    pub open_despite_token: bool,
+}
+
+fn default_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_port() -> u16 {
+    9000
+}
+
+fn default_addresses() -> BTreeMap<String, String> {
+    [
+        ("/mjoy/setup", "setup"),
+        ("/mjoy/start", "start"),
+        ("/mjoy/teams", "teams"),
+        ("/mjoy/pause", "pause"),
+        ("/mjoy/resume", "resume"),
+        ("/mjoy/preset", "load_preset"),
+        ("/mjoy/threshold", "set_threshold"),
+        ("/mjoy/aggregation", "set_aggregation_mode"),
+        ("/mjoy/team/move", "move_player"),
+        ("/mjoy/team/rename", "rename_team"),
+        ("/mjoy/schedule/start", "start_schedule"),
+        ("/mjoy/schedule/pause", "pause_schedule"),
+        ("/mjoy/schedule/resume", "resume_schedule"),
+    ]
+    .into_iter()
+    .map(|(address, command)| (address.to_string(), command.to_string()))
+    .collect()
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        OscConfig {
+            host: default_host(),
+            port: default_port(),
+            addresses: default_addresses(),
+            buttons: BTreeSet::new(),
+            allow_from: Vec::new(),
+            open_despite_token: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum OscError {
+    #[error("packet ends early")]
+    Truncated,
+    #[error("string is not UTF-8")]
+    NotUtf8,
+    #[error("not an OSC message or bundle")]
+    NotOsc,
+    #[error("unsupported argument type {0:?}")]
+    UnsupportedType(char),
+    #[error("nothing mapped to {0}")]
+    UnknownAddress(String),
+    #[error("{address} maps to unknown command {command}")]
+    UnknownCommand { address: String, command: String },
+    #[error("{0} expects {1}")]
+    BadArguments(String, &'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl Arg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Arg::Int(i) => Some(*i as f32),
+            Arg::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Arg::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+/// Reads the 4-byte aligned pieces OSC is made of.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], OscError> {
+        if self.data.len() < len {
+            return Err(OscError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn string(&mut self) -> Result<String, OscError> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(OscError::Truncated)?;
+        let text = std::str::from_utf8(&self.data[..len]).map_err(|_| OscError::NotUtf8)?;
+        let text = text.to_string();
+        // The terminator and padding up to the next multiple of four
+        self.take((len / 4 + 1) * 4)?;
+        Ok(text)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], OscError> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.take(4)?);
+        Ok(word)
+    }
+}
+
+fn decode_message(data: &[u8]) -> Result<Message, OscError> {
+    let mut reader = Reader { data };
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(OscError::NotOsc);
+    }
+    // Very old senders leave out the type tags, treat that as no arguments
+    let tags = if reader.data.is_empty() {
+        String::new()
+    } else {
+        reader.string()?
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip_while(|&c| c == ',') {
+        args.push(match tag {
+            'i' => Arg::Int(i32::from_be_bytes(reader.word()?)),
+            'f' => Arg::Float(f32::from_be_bytes(reader.word()?)),
+            's' => Arg::Str(reader.string()?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            other => return Err(OscError::UnsupportedType(other)),
+        });
+    }
+    Ok(Message { address, args })
+}
+
+/// Every message in a packet, bundles flattened. Time tags are ignored, everything
+/// happens as it arrives.
+pub fn decode(packet: &[u8]) -> Result<Vec<Message>, OscError> {
+    let Some(mut elements) = packet.strip_prefix(b"#bundle\0") else {
+        return Ok(vec![decode_message(packet)?]);
+    };
+    let mut reader = Reader { data: elements };
+    reader.take(8)?;
+    elements = reader.data;
+    let mut messages = Vec::new();
+    while !elements.is_empty() {
+        let mut reader = Reader { data: elements };
+        let len = i32::from_be_bytes(reader.word()?).max(0) as usize;
+        messages.extend(decode(reader.take(len)?)?);
+        elements = reader.data;
+    }
+    Ok(messages)
+}
+
+/// What a button in `OscConfig::buttons` sends when let go.
+fn is_release(args: &[Arg]) -> bool {
+    matches!(args, [arg] if arg.as_f32() == Some(0.0) || *arg == Arg::Bool(false))
+}
+
+impl OscConfig {
+    /// Where to listen. Refused if it would let anyone on the network around the
+    /// server's `admin_token`, unless `allow_from` or `open_despite_token` says so.
+    pub fn listen_address(&self, server: &ServerConfig) -> Result<SocketAddr, std::io::Error> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    format!("{} has no address", self.host),
+                )
+            })?;
+        let address = server.local_address(address);
+        if server.admin_token.is_some()
+            && !address.ip().is_loopback()
+            && self.allow_from.is_empty()
+            && !self.open_despite_token
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "OSC on {} would take commands without the admin_token, set allow_from or open_despite_token",
+                    address
+                ),
+            ));
+        }
+        Ok(address)
+    }
+
+    pub fn allows(&self, sender: IpAddr) -> bool {
+        self.allow_from.is_empty() || self.allow_from.contains(&sender)
+    }
+
+    /// The command a message stands for, None for releases of `buttons`.
+    pub fn command(&self, message: &Message) -> Result<Option<Command>, OscError> {
+        let address = &message.address;
+        let name = self
+            .addresses
+            .get(address)
+            .ok_or_else(|| OscError::UnknownAddress(address.clone()))?;
+        let args = message.args.as_slice();
+        if self.buttons.contains(address) && is_release(args) {
+            return Ok(None);
+        }
+        let bad = |expected| Err(OscError::BadArguments(address.clone(), expected));
+        let simple = match name.as_str() {
+            "setup" => Some(Command::Setup),
+            "start" => Some(Command::Start),
+            "pause" => Some(Command::Pause),
+            "resume" => Some(Command::Resume),
+            "undo_binding" => Some(Command::UndoBinding),
+            "identify" => Some(Command::Identify),
+            "calibrate" => Some(Command::Calibrate),
+            "reload_config" => Some(Command::ReloadConfig),
+            "shutdown" => Some(Command::Shutdown),
+            "start_schedule" => Some(Command::StartSchedule),
+            "pause_schedule" => Some(Command::PauseSchedule),
+            "resume_schedule" => Some(Command::ResumeSchedule),
+            _ => None,
+        };
+        if let Some(command) = simple {
+            return Ok(Some(command));
+        }
+        let command = match (name.as_str(), args) {
+            // Without a count the teams stay as they are
+            ("teams", []) => Command::Teams(None),
+            ("teams", [count]) => match count.as_f32() {
+                Some(count) if count >= 0.0 => Command::Teams(Some(count as usize)),
+                _ => return bad("a team count"),
+            },
+            ("teams", _) => return bad("nothing or a team count"),
+            ("load_preset", [Arg::Str(preset)]) => Command::LoadPreset(preset.clone()),
+            ("load_preset", _) => return bad("a preset name"),
+            ("set_threshold", []) => Command::SetThreshold(ThresholdProfile::default()),
+            ("set_threshold", [threshold]) => {
+                match threshold
+                    .as_f32()
+                    .map(|t| ThresholdProfile::Fixed(t).check())
+                {
+                    Some(Ok(profile)) => Command::SetThreshold(profile),
+                    _ => return bad("a threshold between 0 and 1"),
+                }
+            }
+            ("set_threshold", [min, max]) => match (min.as_f32(), max.as_f32()) {
+                (Some(min), Some(max)) => match (ThresholdProfile::Drift { min, max }).check() {
+                    Ok(profile) => Command::SetThreshold(profile),
+                    Err(_) => return bad("a min between 0 and 1 and a max no lower"),
+                },
+                _ => return bad("a min and a max"),
+            },
+            ("set_threshold", _) => return bad("nothing, a threshold, or a min and max"),
+            ("set_aggregation_mode", [mode]) => {
+                match mode
+                    .as_str()
+                    .and_then(|mode| serde_json::from_value(serde_json::json!(mode)).ok())
+                {
+                    Some(mode) => Command::SetAggregationMode(mode),
+                    None => return bad("threshold, any or all"),
+                }
+            }
+            ("set_aggregation_mode", _) => return bad("threshold, any or all"),
+            ("move_player", [Arg::Str(player), Arg::Str(team)]) => Command::MovePlayer {
+                player: player.clone(),
+                team: team.clone(),
+            },
+            ("move_player", _) => return bad("a player and a team"),
+            ("rename_team", [Arg::Str(team), Arg::Str(name)]) => Command::RenameTeam {
+                team: team.clone(),
+                name: name.clone(),
+            },
+            ("rename_team", _) => return bad("a team and its new name"),
+            _ => {
+                return Err(OscError::UnknownCommand {
+                    address: address.clone(),
+                    command: name.clone(),
+                })
+            }
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Receives OSC packets and passes their commands to main until stopped. Nothing is
+/// sent back, answers only show in the log.
+pub fn field_osc_forever(
+    config: OscConfig,
+    server: &ServerConfig,
+    sender: Sender<Envelope>,
+    stop: StopSignal,
+) -> Result<(), std::io::Error> {
+    let endpoint = config.listen_address(server)?;
+    let socket = UdpSocket::bind(endpoint)?;
+    socket.set_read_timeout(Some(STOP_POLL))?;
+    tracing::info!("OSC listener is listening at {}", endpoint);
+
+    let mut packet = vec![0; MAX_PACKET_LEN];
+    while !stop.is_stopped() {
+        let (len, peer) = match socket.recv_from(&mut packet) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        if !config.allows(peer.ip()) {
+            tracing::warn!("Ignored OSC from {}, not in allow_from", peer);
+            continue;
+        }
+        let messages = match decode(&packet[..len]) {
+            Ok(messages) => messages,
+            Err(err) => {
+                tracing::warn!("Bad OSC packet from {}: {}", peer, err);
+                continue;
+            }
+        };
+        for message in messages {
+            let command = match config.command(&message) {
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!("OSC from {}: {}", peer, err);
+                    continue;
+                }
+            };
+            let (reply, acks) = channel();
+            if sender.send(Envelope { command, reply }).is_err() {
+                tracing::info!("OSC listener stopped, game loop is gone");
+                return Ok(());
+            }
+            match acks.recv_timeout(REPLY_TIMEOUT) {
+                Ok(Ok(_)) => tracing::debug!("OSC: {} -> ok", message.address),
+                Ok(Err(refusal)) => tracing::warn!("OSC: {} -> {}", message.address, refusal),
+                Err(_) => tracing::warn!("OSC: {} -> no answer", message.address),
+            }
+        }
+    }
+    tracing::info!("OSC listener stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AggregationMode;
+
+    fn padded(text: &str) -> Vec<u8> {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.resize((text.len() / 4 + 1) * 4, 0);
+        bytes
+    }
+
+    fn message(address: &str, tags: &str, args: &[&[u8]]) -> Vec<u8> {
+        let mut packet = padded(address);
+        packet.extend(padded(tags));
+        for arg in args {
+            packet.extend_from_slice(arg);
+        }
+        packet
+    }
+
+    #[test]
+    fn decodes_messages_and_bundles() {
+        let teams = message("/mjoy/teams", ",i", &[&3i32.to_be_bytes()]);
+        assert_eq!(
+            decode(&teams).unwrap(),
+            vec![Message {
+                address: "/mjoy/teams".to_string(),
+                args: vec![Arg::Int(3)],
+            }]
+        );
+
+        let team = padded("Dragons");
+        let move_player = message("/mjoy/team/move", ",ss", &[&padded("Egg"), &team]);
+        let start = message("/mjoy/start", ",f", &[&1.0f32.to_be_bytes()]);
+        let mut bundle = padded("#bundle");
+        bundle.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [&move_player, &start] {
+            bundle.extend((element.len() as i32).to_be_bytes());
+            bundle.extend(element);
+        }
+        let messages = decode(&bundle).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].args,
+            vec![Arg::Str("Egg".to_string()), Arg::Str("Dragons".to_string())]
+        );
+
+        assert_eq!(decode(&teams[..14]), Err(OscError::Truncated));
+        assert_eq!(decode(b"junk\0\0\0\0"), Err(OscError::NotOsc));
+    }
+
+    #[test]
+    fn keeps_to_the_server_settings() {
+        let mut server = ServerConfig::default();
+        let mut config = OscConfig::default();
+        assert_eq!(
+            config.listen_address(&server).unwrap(),
+            SocketAddr::from(([0, 0, 0, 0], 9000))
+        );
+        server.localhost_only = true;
+        assert_eq!(
+            config.listen_address(&server).unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 9000))
+        );
+
+        // With a token, OSC only goes beyond loopback when told who to take it from
+        server.localhost_only = false;
+        server.admin_token = Some("s3cret".to_string());
+        assert_eq!(
+            config.listen_address(&server).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        config.allow_from = vec![[192, 168, 1, 20].into()];
+        assert!(config.listen_address(&server).is_ok());
+        assert!(config.allows([192, 168, 1, 20].into()));
+        assert!(!config.allows([192, 168, 1, 21].into()));
+        config.allow_from.clear();
+        config.open_despite_token = true;
+        assert!(config.listen_address(&server).is_ok());
+        assert!(config.allows([192, 168, 1, 21].into()));
+    }
+
+    #[test]
+    fn maps_addresses_to_commands() {
+        let mut config = OscConfig::default();
+        config.buttons.insert("/mjoy/pause".to_string());
+        let command = |address: &str, args: Vec<Arg>| {
+            config.command(&Message {
+                address: address.to_string(),
+                args,
+            })
+        };
+        assert!(matches!(
+            command("/mjoy/start", vec![Arg::Float(1.0)]),
+            Ok(Some(Command::Start))
+        ));
+        // Only buttons have a release to ignore
+        assert!(matches!(
+            command("/mjoy/start", vec![Arg::Float(0.0)]),
+            Ok(Some(Command::Start))
+        ));
+        assert!(matches!(
+            command("/mjoy/pause", vec![Arg::Float(1.0)]),
+            Ok(Some(Command::Pause))
+        ));
+        assert!(matches!(
+            command("/mjoy/pause", vec![Arg::Bool(false)]),
+            Ok(None)
+        ));
+        assert!(matches!(
+            command("/mjoy/teams", vec![Arg::Int(3)]),
+            Ok(Some(Command::Teams(Some(3))))
+        ));
+        assert!(matches!(
+            command("/mjoy/threshold", vec![Arg::Float(0.3), Arg::Float(0.6)]),
+            Ok(Some(Command::SetThreshold(ThresholdProfile::Drift { .. })))
+        ));
+        assert!(matches!(
+            command("/mjoy/aggregation", vec![Arg::Str("all".to_string())]),
+            Ok(Some(Command::SetAggregationMode(AggregationMode::All)))
+        ));
+        assert!(matches!(
+            command("/mjoy/threshold", vec![Arg::Float(2.0)]),
+            Err(OscError::BadArguments(..))
+        ));
+        assert_eq!(
+            command("/mjoy/shutdown", vec![]).unwrap_err(),
+            OscError::UnknownAddress("/mjoy/shutdown".to_string())
+        );
+    }
+}
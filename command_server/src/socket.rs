@@ -0,0 +1,244 @@
+use crate::http::{Request, Response};
+use crate::Shared;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+/// Where the socket goes unless config.json says otherwise, and where `mjoyctl` looks:
+/// the user's runtime directory, or /tmp without one.
+pub fn default_path() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).join("mjoy.sock").display().to_string(),
+        _ => "/tmp/mjoy.sock".to_string(),
+    }
+}
+
+/// One line from a client, naming one of the HTTP routes, e.g.
+/// `{"method": "POST", "path": "/teams", "body": {"teams": 3}}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub body: Value,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// The line sent back: the status and body the route would have answered over HTTP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocketReply {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl SocketRequest {
+    pub fn get(path: &str) -> Self {
+        SocketRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            body: Value::Null,
+        }
+    }
+
+    pub fn post(path: &str, body: Value) -> Self {
+        SocketRequest {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            body,
+        }
+    }
+
+    fn to_http(&self) -> Request {
+        Request {
+            method: self.method.to_uppercase(),
+            path: self.path.clone(),
+            query: String::new(),
+            headers: Vec::new(),
+            body: match &self.body {
+                Value::Null => Vec::new(),
+                body => body.to_string().into_bytes(),
+            },
+        }
+    }
+}
+
+impl From<Response> for SocketReply {
+    fn from(response: Response) -> Self {
+        // Everything but the console and the metrics is JSON already
+        let body = serde_json::from_str(&response.body).unwrap_or(Value::String(response.body));
+        SocketReply {
+            status: response.status,
+            body,
+        }
+    }
+}
+
+fn refuse(status: u16, message: &str) -> SocketReply {
+    SocketReply {
+        status,
+        body: json!({ "error": message }),
+    }
+}
+
+/// Answers one line. Tokens aren't needed, the socket file is only open to its owner.
+fn answer(line: &str, shared: &Shared) -> SocketReply {
+    let request: SocketRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return refuse(400, &format!("bad request line: {}", err)),
+    };
+    if request.path == "/events" {
+        return refuse(400, "the event stream is only served over HTTP");
+    }
+    crate::respond(&request.to_http(), shared).into()
+}
+
+/// Accepts clients until stopped, then removes the socket. A stale socket left by a
+/// crash is replaced, one another instance is still answering on is not.
+pub(crate) fn serve(path: &str, shared: Shared) -> Result<(), std::io::Error> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("{} is in use by another instance", path),
+        ));
+    }
+    let _ = std::fs::remove_file(path);
+    let listener = bind_private(Path::new(path))?;
+    tracing::info!("Control socket is listening at {}", path);
+    shared.stop.wake_socket_on(path);
+
+    // Checked before waiting too, in case the stop came before the wake was set up
+    while !shared.stop.is_stopped() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) if !shared.stop.is_stopped() => stream,
+            Ok(_) => break,
+            Err(err) => {
+                tracing::warn!("Failed to accept a socket client: {}", err);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_client(stream, &shared) {
+                tracing::warn!("Error handling socket client: {:?}", err);
+            }
+        });
+    }
+    let _ = std::fs::remove_file(path);
+    tracing::info!("Control socket stopped");
+    Ok(())
+}
+
+/// Binds `path` so that nobody else can connect, not even briefly: the socket is made
+/// in a directory only we can enter, restricted, and then moved into place.
+fn bind_private(path: &Path) -> Result<UnixListener, std::io::Error> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    bound
+}
+
+/// One reply line per request line, until the client hangs up.
+fn handle_client(stream: UnixStream, shared: &Shared) -> Result<(), std::io::Error> {
+    let reader = BufReader::new(&stream);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _answering = shared.stop.answering();
+        let reply = answer(&line, shared);
+        tracing::debug!("Socket: {} -> {}", line.trim(), reply.status);
+        let mut text = serde_json::to_string(&reply)?;
+        text.push('\n');
+        (&stream).write_all(text.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_become_route_requests() {
+        let request: SocketRequest =
+            serde_json::from_str(r#"{"method": "post", "path": "/teams", "body": {"teams": 3}}"#)
+                .unwrap();
+        let http = request.to_http();
+        assert_eq!(http.method, "POST");
+        assert_eq!(http.json(), Some(json!({ "teams": 3 })));
+
+        let status: SocketRequest = serde_json::from_str(r#"{"path": "/state"}"#).unwrap();
+        assert_eq!(status.method, "GET");
+        assert!(status.to_http().body.is_empty());
+
+        let reply: SocketReply = Response::error(409, "binding is in progress").into();
+        assert_eq!(
+            reply,
+            SocketReply {
+                status: 409,
+                body: json!({ "error": "binding is in progress" }),
+            }
+        );
+    }
+
+    #[test]
+    fn socket_is_only_open_to_its_owner() {
+        let path = std::env::temp_dir().join(format!("mjoy_socket_{}.sock", std::process::id()));
+        let _listener = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixStream::connect(&path).is_ok());
+        // Nothing left behind but the socket itself
+        let staging = path.with_file_name(format!(
+            ".{}.{}",
+            path.file_name().unwrap().to_string_lossy(),
+            std::process::id()
+        ));
+        assert!(!staging.exists());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn stops_with_the_server() {
+        let path = std::env::temp_dir().join(format!("mjoy_stop_{}.sock", std::process::id()));
+        let (sender, _envelopes) = std::sync::mpsc::channel();
+        let stop = crate::StopSignal::default();
+        let shared = Shared {
+            sender,
+            snapshot: Default::default(),
+            events: std::sync::Arc::new(crate::EventHub::new(10.0)),
+            config: Default::default(),
+            metrics: Default::default(),
+            stop: stop.clone(),
+        };
+        let serving = path.display().to_string();
+        let server = thread::spawn(move || serve(&serving, shared));
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(path.exists());
+
+        stop.stop();
+        server.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
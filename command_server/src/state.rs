@@ -0,0 +1,45 @@
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+
+/// What main last published about itself, served by the read-only `/state` endpoints.
+/// Main owns the real data, so the pieces arrive already serialized.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub game_state: Value,
+    pub threshold: f32,
+    /// How team presses are decided, see `AggregationMode`
+    pub aggregation: Value,
+    /// The prompt on screen, e.g. the name being bound
+    pub prompt: Option<String>,
+    pub teams: Value,
+    pub bindings: Value,
+    pub devices: Value,
+    /// The timeline's position and cues, null without one
+    pub schedule: Value,
+}
+
+pub type SharedSnapshot = Arc<RwLock<Snapshot>>;
+
+impl Snapshot {
+    pub fn game(&self) -> Value {
+        json!({
+            "game_state": self.game_state,
+            "threshold": self.threshold,
+            "aggregation": self.aggregation,
+            "prompt": self.prompt,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "game_state": self.game_state,
+            "threshold": self.threshold,
+            "aggregation": self.aggregation,
+            "prompt": self.prompt,
+            "teams": self.teams,
+            "bindings": self.bindings,
+            "devices": self.devices,
+            "schedule": self.schedule,
+        })
+    }
+}
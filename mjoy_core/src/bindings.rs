@@ -1,7 +1,9 @@
 use gilrs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+
+/// How many free names a player can scroll through with the D-pad
+const SHORTLIST_LEN: usize = 5;
 
 #[derive(Clone)]
 pub enum UpdateState {
@@ -9,91 +11,269 @@ pub enum UpdateState {
     Binding(Option<String>),
 }
 
+/// One name handed to one pad, with enough to put things back on undo.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub name: String,
+    pub identity: String,
+    /// What the pad was called before this binding
+    pub replaced_name: Option<String>,
+    /// The pad that held `name` before and lost it to this binding
+    pub previous_holder: Option<String>,
+}
+
+enum Candidate {
+    Skipped,
+    Bound(Binding),
+}
+
+/// Without a list of names to make, names are handed out from the pool until every pad
+/// has one. Setup binds everyone again: pads that had a name wait in `pending`, and
+/// their names stay on offer until they are bound.
 pub struct Binder {
-    bindings_filepath: String,
     bindings_to_make: Option<Vec<String>>,
+    skipped: Vec<String>,
+    cursor: usize,
+    last_dpad: i32,
+    /// Pads holding Select at the last update, so undo happens once per press
+    last_select: Vec<String>,
+    pending: Vec<String>,
+    /// Identity of the only pad that may take the name, when a pad asked to rebind itself
+    only: Option<String>,
+    history: Vec<Binding>,
     next_binding_allowed_time: Option<Instant>,
     next_print_time: Instant,
     cached_state: UpdateState,
 }
 
 impl Binder {
-    pub fn new(bindings_filepath: String) -> Self {
+    pub fn new() -> Self {
         Binder {
-            bindings_filepath,
             bindings_to_make: None,
+            skipped: Vec::new(),
+            cursor: 0,
+            last_dpad: 0,
+            last_select: Vec::new(),
+            pending: Vec::new(),
+            only: None,
+            history: Vec::new(),
             next_binding_allowed_time: None,
             cached_state: UpdateState::Binding(None),
             next_print_time: Instant::now(),
         }
     }
 
+    /// For Setup: every connected pad gets bound, including the ones that have a name.
+    pub fn rebind_everyone(mpl: &crate::joypaths::MinimalPathLookup) -> Self {
+        let mut binder = Self::new();
+        binder.pending = mpl
+            .0
+            .iter()
+            .filter(|(_, np)| np.common_name.is_some())
+            .map(|(identity, _)| identity.clone())
+            .collect();
+        binder
+    }
+
+    /// Offers just `name`, so a player can rebind their own pad without redoing everyone.
+    /// With `only` set, no other pad can take it. Like Setup this starts a new session,
+    /// so earlier bindings can't be undone from it.
+    pub fn rebind_one(name: String, only: Option<String>) -> Self {
+        let mut binder = Self::new();
+        binder.bindings_to_make = Some(vec![name]);
+        binder.only = only;
+        binder
+    }
+
+    /// Reverts the most recent binding and offers its name again. Returns the name.
+    pub fn undo(&mut self, mpl: &mut crate::joypaths::MinimalPathLookup) -> Option<String> {
+        let last = self.history.pop()?;
+
+        if let Some(np) = mpl.0.get_mut(&last.identity) {
+            np.common_name = last.replaced_name.clone();
+        }
+        if let Some(holder) = last.previous_holder.as_ref() {
+            if let Some(np) = mpl.0.get_mut(holder) {
+                np.common_name = Some(last.name.clone());
+            }
+        }
+
+        tracing::info!("Undid binding {} -> {}", last.name, last.identity);
+        match self.bindings_to_make.as_mut() {
+            Some(bindings) => bindings.push(last.name.clone()),
+            // From the pool the name is simply free again, and a pad that had a name was
+            // only up for binding because Setup is redoing everyone
+            None if last.replaced_name.is_some() => self.pending.push(last.identity.clone()),
+            None => (),
+        }
+        self.cached_state = UpdateState::Binding(None);
+        Some(last.name)
+    }
+
+    /// The names on offer right now.
+    fn choices(
+        &self,
+        pool: &crate::name_pool::NamePool,
+        mpl: &crate::joypaths::MinimalPathLookup,
+    ) -> Vec<String> {
+        match self.bindings_to_make.as_ref() {
+            Some(bindings) => bindings.last().cloned().into_iter().collect(),
+            None => pool
+                .free_names(mpl, &self.pending)
+                .into_iter()
+                .filter(|name| !self.skipped.contains(name))
+                .take(SHORTLIST_LEN)
+                .collect(),
+        }
+    }
+
+    /// Connected pads still waiting for a name from the pool.
+    pub fn waiting_pads(
+        &self,
+        gilrs: &gilrs::Gilrs,
+        event_path_lookup: &crate::joypaths::EventPathLookup,
+        mpl: &crate::joypaths::MinimalPathLookup,
+    ) -> Vec<String> {
+        let mut waiting = unbound_pads(gilrs, event_path_lookup, mpl);
+        for (_id, gamepad) in gilrs.gamepads() {
+            let Some(identity) = event_path_lookup.0.get(gamepad.devpath()) else {
+                continue;
+            };
+            if self.pending.contains(identity) && !waiting.contains(identity) {
+                waiting.push(identity.clone());
+            }
+        }
+        waiting.sort();
+        waiting
+    }
+
+    /// Whether any pad started pressing Select since the last update.
+    fn select_pressed(&mut self, gilrs: &gilrs::Gilrs) -> bool {
+        let button_id = crate::injoy::snes_namedbutton_to_id(&crate::injoy::NamedButton::Select);
+        let held = gilrs
+            .gamepads()
+            .filter(|(_id, gamepad)| {
+                gamepad
+                    .button_data(button_id)
+                    .map_or(false, |data| data.value() > 0.9)
+            })
+            .map(|(_id, gamepad)| gamepad.devpath().to_string())
+            .collect();
+        !newly_pressed(&mut self.last_select, held).is_empty()
+    }
+
     pub fn update(
         &mut self,
         gilrs: &gilrs::Gilrs,
         event_path_lookup: &crate::joypaths::EventPathLookup,
         mpl: &mut crate::joypaths::MinimalPathLookup,
+        pool: &crate::name_pool::NamePool,
     ) -> UpdateState {
-        // If bindings_to_make is None, read from the filepath
-        if self.bindings_to_make.is_none() {
-            let file = File::open(&self.bindings_filepath).expect("Unable to open file");
-            let reader = BufReader::new(file);
-            let mut bindings = Vec::new();
-
-            for line in reader.lines() {
-                let line = line.expect("Unable to read line");
-                bindings.push(line);
-            }
-
-            self.bindings_to_make = Some(bindings);
-        }
+        // Read every update so a press during the wait below isn't taken for a new one
+        let select_pressed = self.select_pressed(gilrs);
 
         // Check if binding is allowed now
         if self.next_binding_allowed_time.is_none()
             || self.next_binding_allowed_time.unwrap() <= Instant::now()
         {
-            if let Some(candidate_binding) = self.bindings_to_make.as_mut().unwrap().last().cloned()
+            // Select on any pad takes back the last binding
+            if select_pressed && self.undo(mpl).is_some() {
+                self.next_binding_allowed_time = Some(Instant::now() + Duration::from_millis(250));
+                return self.cached_state.clone();
+            }
+
+            let from_pool = self.bindings_to_make.is_none();
+            let choices = self.choices(pool, mpl);
+            if choices.is_empty()
+                || (from_pool && self.waiting_pads(gilrs, event_path_lookup, mpl).is_empty())
             {
-                if !self.next_print_time.elapsed().is_zero() {
-                    tracing::info!("I'm trying to bind {}", candidate_binding);
-                    self.next_print_time += Duration::from_secs(1);
-                }
+                // No more bindings to process
+                self.cached_state = UpdateState::Done;
+                return self.cached_state.clone();
+            }
 
-                self.cached_state = UpdateState::Binding(Some(candidate_binding.to_owned()));
-
-                match self.perform_candidate_binding(
-                    &candidate_binding,
-                    gilrs,
-                    event_path_lookup,
-                    mpl,
-                ) {
-                    Ok(_) => {
-                        // Binding was successful, remove the candidate from the list and update the time
-                        self.bindings_to_make.as_mut().unwrap().pop();
-                        self.next_binding_allowed_time =
-                            Some(Instant::now() + Duration::from_millis(250));
+            // Left and right on the D-pad scroll through the shortlist, one step per press
+            let dpad = dpad_x(gilrs);
+            let scroll = if from_pool && dpad != self.last_dpad {
+                dpad
+            } else {
+                0
+            };
+            self.last_dpad = dpad;
+            if scroll != 0 {
+                self.cursor =
+                    (self.cursor as i32 + scroll).rem_euclid(choices.len() as i32) as usize;
+                self.next_binding_allowed_time = Some(Instant::now() + Duration::from_millis(250));
+            }
+            let candidate_binding = choices[self.cursor % choices.len()].clone();
+
+            if !self.next_print_time.elapsed().is_zero() {
+                tracing::info!("I'm trying to bind {}", candidate_binding);
+                self.next_print_time += Duration::from_secs(1);
+            }
+
+            self.cached_state = UpdateState::Binding(Some(if choices.len() > 1 {
+                format!("< {} >", candidate_binding)
+            } else {
+                candidate_binding.clone()
+            }));
+            if scroll != 0 {
+                return self.cached_state.clone();
+            }
+
+            match self.perform_candidate_binding(
+                &candidate_binding,
+                gilrs,
+                event_path_lookup,
+                mpl,
+                if from_pool { Some(pool) } else { None },
+            ) {
+                Ok(outcome) => {
+                    match outcome {
+                        Candidate::Bound(binding) => self.history.push(binding),
+                        Candidate::Skipped if from_pool => self.skipped.push(candidate_binding),
+                        Candidate::Skipped => (),
                     }
-                    Err(_) => {
-                        // Binding was not successful, do not pop the candidate and do not update the time
+                    // Binding was successful, remove the candidate from the list and update the time
+                    if let Some(bindings) = self.bindings_to_make.as_mut() {
+                        bindings.pop();
                     }
+                    self.next_binding_allowed_time =
+                        Some(Instant::now() + Duration::from_millis(250));
+                }
+                Err(_) => {
+                    // Binding was not successful, do not pop the candidate and do not update the time
                 }
-            } else {
-                // No more bindings to process
-                self.cached_state = UpdateState::Done;
             }
         }
 
         self.cached_state.clone()
     }
 
-    pub fn perform_candidate_binding(
+    fn perform_candidate_binding(
         &mut self,
         candidate_binding: &str,
         gilrs: &gilrs::Gilrs,
         event_path_lookup: &crate::joypaths::EventPathLookup,
         mpl: &mut crate::joypaths::MinimalPathLookup,
-    ) -> Result<(), ()> {
+        pool: Option<&crate::name_pool::NamePool>,
+    ) -> Result<Candidate, ()> {
         for (_id, gamepad) in gilrs.gamepads() {
+            let identity = event_path_lookup.0.get(gamepad.devpath());
+            if self.only.is_some() && identity != self.only.as_ref() {
+                continue;
+            }
+            // Names from the pool only go to pads that don't have one, or that Setup is
+            // binding again
+            if pool.is_some() {
+                let named = identity
+                    .and_then(|identity| mpl.0.get(identity))
+                    .map_or(false, |np| np.common_name.is_some());
+                if named && !identity.map_or(false, |identity| self.pending.contains(identity)) {
+                    continue;
+                }
+            }
+
             let button_a = crate::injoy::NamedButton::A;
             let button_b = crate::injoy::NamedButton::B;
             let button_id_a: gilrs::Button = crate::injoy::snes_namedbutton_to_id(&button_a);
@@ -109,7 +289,7 @@ impl Binder {
 
             // Check if button B is pressed to skip
             if value_b > 0.9 {
-                return Ok(());
+                return Ok(Candidate::Skipped);
             }
 
             // Check if button A is pressed to perform the binding
@@ -119,20 +299,21 @@ impl Binder {
                 // Correct lookup and mutation process
                 if let Some(devpath_key) = event_path_lookup.0.get(devpath) {
                     if mpl.0.get(devpath_key).is_some() {
-                        mpl.0.get_mut(devpath_key).unwrap().common_name =
-                            Some(candidate_binding.to_string());
-
-                        mpl.0.retain(|k, v| {
-                            if devpath_key != k {
-                                match v.common_name.as_ref() {
-                                    Some(s) => s != candidate_binding,
-                                    None => true,
-                                }
-                            } else {
-                                true
-                            }
-                        });
+                        // A regular gets their old name rather than the one on offer, unless
+                        // they are picking a name again at Setup
+                        let candidate_binding = pool
+                            .filter(|_| !self.pending.contains(devpath_key))
+                            .and_then(|pool| pool.returning_name(devpath_key, mpl))
+                            .unwrap_or_else(|| candidate_binding.to_string());
+                        let candidate_binding = candidate_binding.as_str();
+                        let replaced_name = mpl
+                            .0
+                            .get_mut(devpath_key)
+                            .unwrap()
+                            .common_name
+                            .replace(candidate_binding.to_string());
 
+                        let mut previous_holder = None;
                         for (k, v) in mpl.0.iter_mut() {
                             if k != devpath_key {
                                 let should_un_name_them = if let Some(cn) = v.common_name.as_ref() {
@@ -143,11 +324,18 @@ impl Binder {
 
                                 if should_un_name_them {
                                     v.common_name = None;
+                                    previous_holder = Some(k.clone());
                                 }
                             }
                         }
 
-                        return Ok(());
+                        self.pending.retain(|identity| identity != devpath_key);
+                        return Ok(Candidate::Bound(Binding {
+                            name: candidate_binding.to_string(),
+                            identity: devpath_key.clone(),
+                            replaced_name,
+                            previous_holder,
+                        }));
                     }
                 }
             }
@@ -155,3 +343,199 @@ impl Binder {
         Err(())
     }
 }
+
+/// What a pad can ask for outside a game, by holding Start and pressing another button.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gesture {
+    /// Start + X: "press any button to see your name", or back out of it
+    Identify,
+    /// Start + Y: rebind the name of the pad with this identity
+    RebindOwn(String),
+}
+
+/// Turns held chords into one `Gesture` per press, the way `last_dpad` does for scrolling.
+#[derive(Default)]
+pub struct Gestures {
+    held: Vec<(String, Gesture)>,
+}
+
+impl Gestures {
+    pub fn poll(
+        &mut self,
+        gilrs: &gilrs::Gilrs,
+        event_path_lookup: &crate::joypaths::EventPathLookup,
+    ) -> Option<Gesture> {
+        use crate::injoy::NamedButton;
+        let mut held = Vec::new();
+        for (_id, gamepad) in gilrs.gamepads() {
+            let pressed = |button: NamedButton| {
+                gamepad
+                    .button_data(crate::injoy::snes_namedbutton_to_id(&button))
+                    .map_or(false, |data| data.value() > 0.9)
+            };
+            if !pressed(NamedButton::Start) {
+                continue;
+            }
+            let devpath = gamepad.devpath().to_string();
+            if pressed(NamedButton::X) {
+                held.push((devpath.clone(), Gesture::Identify));
+            }
+            if pressed(NamedButton::Y) {
+                if let Some(identity) = event_path_lookup.0.get(gamepad.devpath()) {
+                    held.push((devpath, Gesture::RebindOwn(identity.clone())));
+                }
+            }
+        }
+        self.press(held)
+    }
+
+    /// `held` pairs each pad's devpath with a chord it holds.
+    fn press(&mut self, held: Vec<(String, Gesture)>) -> Option<Gesture> {
+        newly_pressed(&mut self.held, held)
+            .into_iter()
+            .next()
+            .map(|(_, gesture)| gesture)
+    }
+}
+
+/// -1 for left and 1 for right on any pad's D-pad, read the way team selection does.
+fn dpad_x(gilrs: &gilrs::Gilrs) -> i32 {
+    for (_id, gamepad) in gilrs.gamepads() {
+        match gamepad
+            .button_data(gilrs::Button::DPadRight)
+            .map(|data| data.value())
+        {
+            Some(v) if v < 0.1 => return -1,
+            Some(v) if v > 0.9 => return 1,
+            _ => (),
+        }
+    }
+    0
+}
+
+/// Remembers what is held now and returns what wasn't held last time.
+fn newly_pressed<T: PartialEq + Clone>(last: &mut Vec<T>, held: Vec<T>) -> Vec<T> {
+    let pressed = held
+        .iter()
+        .filter(|item| !last.contains(item))
+        .cloned()
+        .collect();
+    *last = held;
+    pressed
+}
+
+/// "Press any button to see your name": the name (or identity, if unbound) of a pad
+/// that currently has a button held.
+pub fn identify(
+    gilrs: &gilrs::Gilrs,
+    event_path_lookup: &crate::joypaths::EventPathLookup,
+    mpl: &crate::joypaths::MinimalPathLookup,
+) -> Option<String> {
+    for (_id, gamepad) in gilrs.gamepads() {
+        let pressed = crate::injoy::NamedButton::iter().any(|button| {
+            let button_id = crate::injoy::snes_namedbutton_to_id(&button);
+            gamepad
+                .button_data(button_id)
+                .map_or(false, |data| data.value() > 0.9)
+        });
+        if !pressed {
+            continue;
+        }
+        let Some(identity) = event_path_lookup.0.get(gamepad.devpath()) else {
+            continue;
+        };
+        return match mpl.0.get(identity).and_then(|np| np.common_name.clone()) {
+            Some(name) => Some(name),
+            None => Some(format!("Unbound ({})", identity)),
+        };
+    }
+    None
+}
+
+/// Identities of connected pads that have no name yet.
+pub fn unbound_pads(
+    gilrs: &gilrs::Gilrs,
+    event_path_lookup: &crate::joypaths::EventPathLookup,
+    mpl: &crate::joypaths::MinimalPathLookup,
+) -> Vec<String> {
+    let mut unbound = Vec::new();
+    for (_id, gamepad) in gilrs.gamepads() {
+        let Some(identity) = event_path_lookup.0.get(gamepad.devpath()) else {
+            continue;
+        };
+        match mpl.0.get(identity) {
+            Some(np) if np.common_name.is_some() => (),
+            _ => unbound.push(identity.clone()),
+        }
+    }
+    unbound.sort();
+    unbound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mpl;
+
+    #[test]
+    fn undo_restores_both_pads() {
+        // "Star" moved from pad 2 to pad 1, which used to be "Coin"
+        let mut mpl = mpl(&[("1:1.0", Some("Star")), ("2:1.0", None)]);
+        let mut binder = Binder::new();
+        binder.bindings_to_make = Some(vec![]);
+        binder.history.push(Binding {
+            name: "Star".to_string(),
+            identity: "1:1.0".to_string(),
+            replaced_name: Some("Coin".to_string()),
+            previous_holder: Some("2:1.0".to_string()),
+        });
+
+        assert_eq!(binder.undo(&mut mpl), Some("Star".to_string()));
+        assert_eq!(mpl.0["1:1.0"].common_name.as_deref(), Some("Coin"));
+        assert_eq!(mpl.0["2:1.0"].common_name.as_deref(), Some("Star"));
+        // Offered again next
+        assert_eq!(binder.bindings_to_make, Some(vec!["Star".to_string()]));
+        assert_eq!(binder.undo(&mut mpl), None);
+    }
+
+    #[test]
+    fn setup_rebinds_named_pads() {
+        let mut mpl = mpl(&[("1:1.0", Some("Coin")), ("2:1.0", None)]);
+        let mut binder = Binder::rebind_everyone(&mpl);
+        assert_eq!(binder.pending, vec!["1:1.0".to_string()]);
+
+        // Pad 1 went from Star to Coin, undoing that puts it back in line
+        binder.pending.clear();
+        binder.history.push(Binding {
+            name: "Coin".to_string(),
+            identity: "1:1.0".to_string(),
+            replaced_name: Some("Star".to_string()),
+            previous_holder: None,
+        });
+        assert_eq!(binder.undo(&mut mpl), Some("Coin".to_string()));
+        assert_eq!(mpl.0["1:1.0"].common_name.as_deref(), Some("Star"));
+        assert_eq!(binder.pending, vec!["1:1.0".to_string()]);
+    }
+
+    #[test]
+    fn gestures_fire_once_per_press() {
+        let mut gestures = Gestures::default();
+        let identify = || vec![("event3".to_string(), Gesture::Identify)];
+        assert_eq!(gestures.press(identify()), Some(Gesture::Identify));
+        assert_eq!(gestures.press(identify()), None);
+        // Another pad joining in still counts
+        let mut both = identify();
+        both.push(("event4".to_string(), Gesture::Identify));
+        assert_eq!(gestures.press(both), Some(Gesture::Identify));
+        assert_eq!(gestures.press(vec![]), None);
+        assert_eq!(gestures.press(identify()), Some(Gesture::Identify));
+    }
+
+    #[test]
+    fn rebind_one_only_offers_that_name() {
+        let binder = Binder::rebind_one("Egg".to_string(), Some("1:1.0".to_string()));
+        assert_eq!(binder.bindings_to_make, Some(vec!["Egg".to_string()]));
+        assert_eq!(binder.only.as_deref(), Some("1:1.0"));
+        assert!(binder.history.is_empty());
+    }
+}
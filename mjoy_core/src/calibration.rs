@@ -0,0 +1,240 @@
+use crate::injoy::{snes_namedbutton_to_id, NamedButton};
+use crate::persist::{PersistError, Store};
+use gilrs::ev::Code;
+use gilrs::{Axis, Button, Gamepad, GamepadId, Gilrs, Mapping};
+use std::collections::BTreeMap;
+use strum::IntoEnumIterator;
+
+/// SDL2 mapping strings for pads whose buttons don't match `snes_namedbutton_to_id`,
+/// keyed by the gamepad UUID in the hex form SDL uses.
+pub struct Calibrations {
+    store: Store,
+    mappings: BTreeMap<String, String>,
+}
+
+/// The database gilrs bundles. gilrs adds it, and `SDL_GAMECONTROLLERCONFIG`, after
+/// the builder's own mappings, so by default they would replace a calibration of any
+/// pad they know, such as the 0079:0011 SNES pads.
+const BUNDLED_MAPPINGS: &str =
+    include_str!("../../vendored/gilrs/gilrs/SDL_GameControllerDB/gamecontrollerdb.txt");
+
+pub fn uuid_key(uuid: [u8; 16]) -> String {
+    uuid.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Calibrations {
+    /// Starts with no calibrations until `load` succeeds.
+    pub fn new(store: Store) -> Self {
+        Calibrations {
+            store,
+            mappings: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), PersistError> {
+        self.mappings = self.store.load()?.unwrap_or_default();
+        Ok(())
+    }
+
+    /// All mappings, one per line, ready for `GilrsBuilder::add_mappings`.
+    pub fn sdl_mappings(&self) -> String {
+        self.mappings
+            .values()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Everything for a `GilrsBuilder` built without its included and env mappings, in
+    /// rising priority: the bundled database, `env` (`SDL_GAMECONTROLLERCONFIG`), then
+    /// calibrations. gilrs keeps the last mapping it reads for a UUID.
+    pub fn builder_mappings(&self, env: Option<&str>) -> String {
+        [
+            BUNDLED_MAPPINGS,
+            env.unwrap_or_default(),
+            &self.sdl_mappings(),
+        ]
+        .join("\n")
+    }
+
+    /// Replaces any mapping for the same UUID.
+    pub fn save(&mut self, uuid: &str, sdl_mapping: &str) -> Result<(), PersistError> {
+        self.mappings
+            .insert(uuid.to_string(), sdl_mapping.to_string());
+        self.store.save(&self.mappings)?;
+        Ok(())
+    }
+}
+
+pub enum CalibrationState {
+    /// Still waiting, with the prompt to show
+    Prompt(String),
+    /// UUID key and the SDL2 mapping now active for that pad
+    Done(String, String),
+    Failed(String),
+}
+
+/// Walks one pad through pressing every `NamedButton` and records which raw code each
+/// press came from. The first pad to press anything is the one being calibrated.
+pub struct Calibrator {
+    gamepad: Option<GamepadId>,
+    mapping: Mapping,
+    remaining: Vec<NamedButton>,
+    captured: Vec<Code>,
+}
+
+/// Everything gilrs maps that calibration doesn't ask about, kept as it was. C, Z,
+/// LeftZ and RightZ have no SDL2 name, so they are left out to keep the mapping SDL2's.
+const UNCALIBRATED_BUTTONS: [Button; 9] = [
+    Button::LeftTrigger2,
+    Button::RightTrigger2,
+    Button::Mode,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::DPadLeft,
+    Button::DPadRight,
+];
+
+const AXES: [Axis; 6] = [
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::RightStickX,
+    Axis::RightStickY,
+    Axis::DPadX,
+    Axis::DPadY,
+];
+
+fn existing_mapping(gamepad: &Gamepad) -> Mapping {
+    let mut mapping = Mapping::new();
+    for button in UNCALIBRATED_BUTTONS {
+        if let Some(code) = gamepad.button_code(button) {
+            mapping.insert_btn(code, button);
+        }
+    }
+    for axis in AXES {
+        if let Some(code) = gamepad.axis_code(axis) {
+            mapping.insert_axis(code, axis);
+        }
+    }
+    mapping
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Calibrator {
+            gamepad: None,
+            mapping: Mapping::new(),
+            remaining: NamedButton::iter().collect(),
+            captured: Vec::new(),
+        }
+    }
+
+    pub fn prompt(&self) -> String {
+        match (self.gamepad, self.remaining.first()) {
+            (None, _) => "Press any button on the pad to calibrate".to_string(),
+            (Some(_), Some(button)) => format!("Calibrating: press {:?}", button),
+            (Some(_), None) => "Calibration done".to_string(),
+        }
+    }
+
+    pub fn handle_event(&mut self, gilrs: &mut Gilrs, event: &gilrs::Event) -> CalibrationState {
+        let gilrs::EventType::ButtonPressed(_, code) = event.event else {
+            return CalibrationState::Prompt(self.prompt());
+        };
+
+        let Some(id) = self.gamepad else {
+            let gamepad = gilrs.gamepad(event.id);
+            tracing::info!("Calibrating {} ({})", gamepad.name(), gamepad.devpath());
+            self.mapping = existing_mapping(&gamepad);
+            self.gamepad = Some(event.id);
+            return CalibrationState::Prompt(self.prompt());
+        };
+        // Ignore other pads, and a button that has already been given a job
+        if event.id != id || self.captured.contains(&code) {
+            return CalibrationState::Prompt(self.prompt());
+        }
+
+        let button = self.remaining.remove(0);
+        self.mapping
+            .insert_btn(code, snes_namedbutton_to_id(&button));
+        self.captured.push(code);
+        if !self.remaining.is_empty() {
+            return CalibrationState::Prompt(self.prompt());
+        }
+
+        let uuid = uuid_key(gilrs.gamepad(id).uuid());
+        match gilrs.set_mapping_strict(usize::from(id), &self.mapping, None) {
+            Ok(sdl_mapping) => CalibrationState::Done(uuid, sdl_mapping),
+            Err(e) => CalibrationState::Failed(format!("Calibration failed: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn uuid_key_is_sdl_hex() {
+        let mut uuid = [0u8; 16];
+        uuid[0] = 0x03;
+        uuid[4] = 0x79;
+        uuid[8] = 0x11;
+        assert_eq!(uuid_key(uuid), "03000000790000001100000000000000");
+    }
+
+    #[test]
+    fn save_replaces_and_reloads() {
+        let dir = TempDir::new("calibration");
+        let path = dir.join("calibrations.json");
+
+        let mut calibrations = Calibrations::new(Store::new(&path, 0));
+        calibrations.load().unwrap();
+        calibrations
+            .save("0300", "0300,Old Pad,a:b1,b:b2,platform:Linux,")
+            .unwrap();
+        calibrations
+            .save("0300", "0300,USB Gamepad,a:b2,b:b1,platform:Linux,")
+            .unwrap();
+        calibrations
+            .save("0500", "0500,Arcade Stick,a:b0,platform:Linux,")
+            .unwrap();
+
+        let mut calibrations = Calibrations::new(Store::new(&path, 0));
+        calibrations.load().unwrap();
+        assert_eq!(
+            calibrations.sdl_mappings(),
+            "0300,USB Gamepad,a:b2,b:b1,platform:Linux,\n0500,Arcade Stick,a:b0,platform:Linux,"
+        );
+    }
+
+    #[test]
+    fn calibrations_win_over_bundled_mappings() {
+        let dir = TempDir::new("calibration_priority");
+        let snes = "03000000790000001100000010010000";
+        assert!(BUNDLED_MAPPINGS.contains(snes));
+
+        let mut calibrations = Calibrations::new(Store::new(dir.join("calibrations.json"), 0));
+        let ours = format!("{},USB Gamepad,a:b2,b:b1,platform:Linux,", snes);
+        calibrations.save(snes, &ours).unwrap();
+        let env = format!("{},From Env,a:b0,platform:Linux,", snes);
+        let mappings = calibrations.builder_mappings(Some(&env));
+        let last = mappings
+            .lines()
+            .filter(|line| line.starts_with(snes))
+            .last();
+        assert_eq!(last, Some(ours.as_str()));
+
+        // Without a calibration the env mapping beats the bundled one, as in gilrs
+        let none = Calibrations::new(Store::new(dir.join("none.json"), 0));
+        let mappings = none.builder_mappings(Some(&env));
+        let last = mappings
+            .lines()
+            .filter(|line| line.starts_with(snes))
+            .last();
+        assert_eq!(last, Some(env.as_str()));
+    }
+}
@@ -0,0 +1,255 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Matches a device when every field that is set matches. Names and paths are globs
+/// (`*` and `?`), vendor and product are hex USB ids such as "0079". Both are checked
+/// when the config is read, so a typo fails loading instead of never matching.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DeviceRule {
+    #[serde(default)]
+    pub name: Option<Glob>,
+    #[serde(default)]
+    pub vendor: Option<UsbId>,
+    #[serde(default)]
+    pub product: Option<UsbId>,
+    #[serde(default)]
+    pub path: Option<Glob>,
+}
+
+/// A glob compiled once, written in the config as the pattern itself.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Glob {
+    pattern: String,
+    re: Regex,
+}
+
+/// A USB vendor or product id, written in the config in hex with or without "0x".
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct UsbId(pub u16);
+
+/// An empty allow list lets everything in. Deny rules always win.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DeviceFilter {
+    #[serde(default)]
+    pub allow: Vec<DeviceRule>,
+    #[serde(default)]
+    pub deny: Vec<DeviceRule>,
+}
+
+/// What is known about a device at the point it is checked. Unknown fields never match
+/// a rule that asks for them.
+#[derive(Debug, Default)]
+pub struct DeviceInfo<'a> {
+    pub name: Option<&'a str>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub path: Option<&'a str>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        let mut re = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => re.push_str(".*"),
+                '?' => re.push('.'),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        Ok(Glob {
+            pattern: pattern.to_string(),
+            re: Regex::new(&re)?,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.re.is_match(text)
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Glob::new(&pattern)
+    }
+}
+
+impl From<Glob> for String {
+    fn from(glob: Glob) -> Self {
+        glob.pattern
+    }
+}
+
+impl TryFrom<String> for UsbId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        u16::from_str_radix(id.trim_start_matches("0x"), 16)
+            .map(UsbId)
+            .map_err(|_| format!("{:?} is not a hex USB id", id))
+    }
+}
+
+impl From<UsbId> for String {
+    fn from(id: UsbId) -> Self {
+        format!("{:04x}", id.0)
+    }
+}
+
+impl DeviceRule {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        if let Some(glob) = &self.name {
+            match info.name {
+                Some(name) if glob.is_match(name) => (),
+                _ => return false,
+            }
+        }
+        if let Some(vendor) = self.vendor {
+            if info.vendor != Some(vendor.0) {
+                return false;
+            }
+        }
+        if let Some(product) = self.product {
+            if info.product != Some(product.0) {
+                return false;
+            }
+        }
+        if let Some(glob) = &self.path {
+            match info.path {
+                Some(path) if glob.is_match(path) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl DeviceFilter {
+    pub fn allows(&self, info: &DeviceInfo) -> bool {
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(info)) {
+            return false;
+        }
+        !self.deny.iter().any(|rule| rule.matches(info))
+    }
+
+    /// Drops gamepads the filter rejects from the lookup, so every gilrs loop that goes
+    /// through it ignores them. Discovery can't always read a device's name, gilrs can.
+    pub fn prune(
+        &self,
+        gilrs: &gilrs::Gilrs,
+        epl: &mut crate::joypaths::EventPathLookup,
+        mpl: &crate::joypaths::MinimalPathLookup,
+    ) {
+        for (_id, gamepad) in gilrs.gamepads() {
+            let devpath = gamepad.devpath();
+            let Some(identity) = epl.0.get(devpath) else {
+                continue;
+            };
+            let path = mpl.0.get(identity).map(|np| np.full_path.as_str());
+
+            // gilrs UUIDs carry the USB ids little endian, like SDL GUIDs
+            let uuid = gamepad.uuid();
+            let info = DeviceInfo {
+                name: Some(gamepad.os_name()),
+                vendor: Some(u16::from_le_bytes([uuid[4], uuid[5]])),
+                product: Some(u16::from_le_bytes([uuid[8], uuid[9]])),
+                path,
+            };
+            if !self.allows(&info) {
+                tracing::info!(
+                    "Ignoring {} ({}) by device filter",
+                    gamepad.os_name(),
+                    devpath
+                );
+                epl.0.remove(devpath);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad<'a>(name: &'a str, vendor: u16, product: u16) -> DeviceInfo<'a> {
+        DeviceInfo {
+            name: Some(name),
+            vendor: Some(vendor),
+            product: Some(product),
+            path: Some("/dev/input/by-path/pci-0000:00:14.0-usb-0:1:1.0-event-joystick"),
+        }
+    }
+
+    #[test]
+    fn empty_filter_allows_everything() {
+        let filter = DeviceFilter::default();
+        assert!(filter.allows(&pad("USB Gamepad", 0x0079, 0x0011)));
+        assert!(filter.allows(&DeviceInfo::default()));
+    }
+
+    #[test]
+    fn deny_by_name_glob() {
+        let filter = DeviceFilter {
+            allow: vec![],
+            deny: vec![DeviceRule {
+                name: Some(Glob::new("Buster*").unwrap()),
+                ..Default::default()
+            }],
+        };
+        assert!(!filter.allows(&pad("Buster0", 0, 0)));
+        assert!(filter.allows(&pad("USB Gamepad", 0x0079, 0x0011)));
+        // Nothing is known about the name, so the rule can't match
+        assert!(filter.allows(&DeviceInfo::default()));
+    }
+
+    #[test]
+    fn allow_by_vendor_and_product() {
+        let filter: DeviceFilter =
+            serde_json::from_str(r#"{"allow": [{"vendor": "0079", "product": "0x0011"}]}"#)
+                .unwrap();
+        assert!(filter.allows(&pad("USB Gamepad", 0x0079, 0x0011)));
+        assert!(!filter.allows(&pad("Arcade Stick", 0x0079, 0x0006)));
+        assert!(!filter.allows(&DeviceInfo::default()));
+    }
+
+    #[test]
+    fn rules_are_checked_on_load() {
+        let typo = serde_json::from_str::<DeviceFilter>(r#"{"deny": [{"vendor": "07g9"}]}"#);
+        assert!(typo.unwrap_err().to_string().contains("not a hex USB id"));
+        let round_trip: DeviceFilter = serde_json::from_str(
+            &serde_json::to_string(&DeviceFilter {
+                allow: vec![],
+                deny: vec![DeviceRule {
+                    vendor: Some(UsbId(0x0079)),
+                    name: Some(Glob::new("Buster*").unwrap()),
+                    ..Default::default()
+                }],
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(round_trip.deny[0].vendor, Some(UsbId(0x0079)));
+        assert!(!round_trip.allows(&pad("Buster0", 0x0079, 0x0011)));
+        assert!(round_trip.allows(&pad("USB Gamepad", 0x0079, 0x0011)));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = DeviceFilter {
+            allow: vec![DeviceRule {
+                path: Some(Glob::new("*usb-0:1:*").unwrap()),
+                ..Default::default()
+            }],
+            deny: vec![DeviceRule {
+                name: Some(Glob::new("*Wheel*").unwrap()),
+                ..Default::default()
+            }],
+        };
+        assert!(filter.allows(&pad("USB Gamepad", 0x0079, 0x0011)));
+        assert!(!filter.allows(&pad("Racing Wheel", 0x046d, 0xc29b)));
+    }
+}
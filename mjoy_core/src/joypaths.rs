@@ -1,17 +1,34 @@
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, BufWriter, Write};
+use std::fs;
+use std::path::Path;
 use tracing;
 
+/// How a controller is recognised across unplugs and port changes.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityStrategy {
+    /// The USB port the pad is plugged into. Moving ports makes a new player.
+    #[default]
+    PortPath,
+    /// The device's uniq/serial string, falling back to the port when a pad has none or
+    /// shares it with another pad.
+    Uniq,
+    /// The gilrs UUID (bus, vendor, product, version) plus an ordinal for identical pads.
+    GilrsUuid,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NamedPath {
     pub full_path: String,
     pub minimal_path: String,
     pub root_event_path: String,
     pub common_name: Option<String>,
+    /// Key in `MinimalPathLookup`. Missing in bindings written before identities existed,
+    /// in which case the port based `minimal_path` was the key.
+    #[serde(default)]
+    pub identity: String,
 }
 
 #[derive(Debug)]
@@ -20,125 +37,631 @@ pub struct EventPathLookup(pub HashMap<String, String>);
 #[derive(Debug)]
 pub struct MinimalPathLookup(pub HashMap<String, NamedPath>);
 
-pub fn repath(config: &crate::Config) -> Vec<NamedPath> {
-    let paths = fs::read_dir("/dev/input/by-path").expect("Failed to read /dev/input/by-path");
-    let is_event_joy = Regex::new(r"event-joystick").expect("Failed to compile regex");
-    let path_only = Regex::new(r"/dev/input/by-path/pci.*usb.*:(.*:1)\.([0-9])-event-joystick")
-        .expect("Failed to compile regex");
-    let gimme_event = Regex::new(r"../event([0-9]+)").expect("Failed to compile regex");
+#[derive(Debug, thiserror::Error)]
+pub enum JoypathsError {
+    #[error("Failed to list devices in {path}: {source}")]
+    ReadDir {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Device path is not valid unicode: {0:?}")]
+    NonUnicodePath(std::path::PathBuf),
+    #[error("Unrecognised joystick path: {0}")]
+    UnrecognisedPath(String),
+    #[error("Bad controller number in {0}")]
+    BadControllerNumber(String),
+    #[error("Failed to resolve {path}: {source}")]
+    ReadLink {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("No event device behind {0}")]
+    NoEventDevice(String),
+    #[error("Failed to persist bindings: {0}")]
+    Persist(#[from] crate::persist::PersistError),
+}
+
+struct PathRegexes {
+    is_event_joy: Regex,
+    path_only: Regex,
+    gimme_event: Regex,
+}
+
+/// Scans `root`/dev/input/by-path for joysticks. `root` is "/" outside of tests.
+pub fn repath(config: &crate::Config, root: &Path) -> Result<Vec<NamedPath>, JoypathsError> {
+    let by_path = root.join("dev/input/by-path");
+    let paths = fs::read_dir(&by_path).map_err(|source| JoypathsError::ReadDir {
+        path: by_path.to_string_lossy().to_string(),
+        source,
+    })?;
+    let regexes = PathRegexes {
+        is_event_joy: Regex::new(r"event-joystick").expect("Failed to compile regex"),
+        path_only: Regex::new(r"/dev/input/by-path/pci.*usb.*:(.*:1)\.([0-9])-event-joystick")
+            .expect("Failed to compile regex"),
+        gimme_event: Regex::new(r"../event([0-9]+)").expect("Failed to compile regex"),
+    };
 
     let mut discovered_paths = Vec::new();
 
     for path in paths {
-        let path = path.expect("Path conversion failed").path();
-        let full_path = path.to_str().expect("Path to string failed");
-
-        if is_event_joy.is_match(full_path) {
-            let partial_minimal_path = path_only
-                .captures(full_path)
-                .unwrap()
-                .get(1)
-                .unwrap()
-                .as_str()
-                .to_string();
-            let multi_controller = path_only
-                .captures(full_path)
-                .unwrap()
-                .get(2)
-                .unwrap()
-                .as_str()
-                .to_string();
-
-            if config.number_of_multi_port_controllers_to_use
-                <= multi_controller.parse::<u32>().unwrap()
-            {
+        // A pad unplugged mid-scan shows up as a failed entry, skip it and carry on
+        let path = match path {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                tracing::warn!("Skipping unreadable device entry: {}", e);
                 continue;
             }
+        };
 
-            let minimal_path = format!("{}.{}", partial_minimal_path, multi_controller);
-            let js_path = fs::read_link(full_path)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            let eventpath = format!(
-                "/dev/input/event{}",
-                gimme_event
-                    .captures(&js_path)
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-            );
+        match discover_device(config, root, &regexes, path) {
+            Ok(Some(np)) => discovered_paths.push(np),
+            Ok(None) => (),
+            Err(e) => tracing::warn!("Skipping device: {}", e),
+        }
+    }
 
-            discovered_paths.push(NamedPath {
-                full_path: full_path.to_string(),
-                minimal_path: minimal_path.clone(),
-                root_event_path: eventpath,
-                common_name: None,
-            });
+    if config.identity_strategy == IdentityStrategy::Uniq {
+        // Some pads all report the same serial, which tells them apart from nothing
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for np in discovered_paths.iter() {
+            *counts.entry(np.identity.clone()).or_insert(0) += 1;
+        }
+        for np in discovered_paths.iter_mut() {
+            if counts[&np.identity] > 1 {
+                tracing::warn!(
+                    "{} is shared by several pads, falling back to port path identity",
+                    np.identity
+                );
+                np.identity = np.minimal_path.clone();
+            }
         }
     }
 
-    discovered_paths
+    if config.identity_strategy == IdentityStrategy::GilrsUuid {
+        // Identical pads share a UUID, so number them in port order
+        discovered_paths.sort_by(|a, b| a.minimal_path.cmp(&b.minimal_path));
+        let mut seen: HashMap<String, u32> = HashMap::new();
+        for np in discovered_paths.iter_mut() {
+            if np.identity == np.minimal_path {
+                continue;
+            }
+            let ordinal = seen.entry(np.identity.clone()).or_insert(0);
+            np.identity = format!("{}#{}", np.identity, ordinal);
+            *ordinal += 1;
+        }
+    }
+
+    Ok(discovered_paths)
 }
 
-impl MinimalPathLookup {
-    pub fn read_from_disk(file_path: &str) -> Self {
-        match File::open(file_path) {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                // Specify that the deserialized type should be Vec<NamedPath>
-                match serde_json::from_reader::<_, Vec<NamedPath>>(reader) {
-                    Ok(named_paths) => {
-                        let lookup = named_paths
-                            .into_iter()
-                            .map(|np| (np.minimal_path.clone(), np))
-                            .collect();
-                        MinimalPathLookup(lookup)
-                    }
-                    Err(e) => {
-                        tracing::warn!("Failed to parse bindings: {}", e);
-                        MinimalPathLookup(HashMap::new())
-                    }
-                }
+fn discover_device(
+    config: &crate::Config,
+    root: &Path,
+    regexes: &PathRegexes,
+    path: std::path::PathBuf,
+) -> Result<Option<NamedPath>, JoypathsError> {
+    let full_path = match path.to_str() {
+        Some(p) => p,
+        None => return Err(JoypathsError::NonUnicodePath(path)),
+    };
+
+    if !regexes.is_event_joy.is_match(full_path) {
+        return Ok(None);
+    }
+
+    let captures = regexes
+        .path_only
+        .captures(full_path)
+        .ok_or_else(|| JoypathsError::UnrecognisedPath(full_path.to_string()))?;
+    let partial_minimal_path = captures[1].to_string();
+    let multi_controller = captures[2].to_string();
+
+    let controller_number = multi_controller
+        .parse::<u32>()
+        .map_err(|_| JoypathsError::BadControllerNumber(full_path.to_string()))?;
+    if config.number_of_multi_port_controllers_to_use <= controller_number {
+        return Ok(None);
+    }
+
+    let minimal_path = format!("{}.{}", partial_minimal_path, multi_controller);
+    let js_path = fs::read_link(full_path).map_err(|source| JoypathsError::ReadLink {
+        path: full_path.to_string(),
+        source,
+    })?;
+    let js_path = js_path.to_string_lossy();
+    let event_number = regexes
+        .gimme_event
+        .captures(&js_path)
+        .ok_or_else(|| JoypathsError::NoEventDevice(full_path.to_string()))?[1]
+        .to_string();
+
+    let name = read_sysfs_attr(root, &event_number, "name");
+    let usb_id = |field: &str| {
+        let value = read_sysfs_attr(root, &event_number, &format!("id/{}", field))?;
+        u16::from_str_radix(&value, 16).ok()
+    };
+    let info = crate::device_filter::DeviceInfo {
+        name: name.as_deref(),
+        vendor: usb_id("vendor"),
+        product: usb_id("product"),
+        path: Some(full_path),
+    };
+    if !config.device_filter.allows(&info) {
+        tracing::info!("Ignoring {} by device filter", full_path);
+        return Ok(None);
+    }
+
+    let eventpath = root
+        .join(format!("dev/input/event{}", event_number))
+        .to_string_lossy()
+        .to_string();
+
+    let identity = match config.identity_strategy {
+        IdentityStrategy::PortPath => minimal_path.clone(),
+        IdentityStrategy::Uniq => match read_uniq(root, &event_number) {
+            Some(uniq) => format!("uniq:{}", uniq),
+            None => {
+                tracing::warn!(
+                    "No uniq for {}, falling back to port path identity",
+                    full_path
+                );
+                minimal_path.clone()
             }
-            Err(_) => {
+        },
+        // The ordinal is appended by `repath` once every device is known
+        IdentityStrategy::GilrsUuid => match read_uuid(root, &event_number) {
+            Some(uuid) => uuid,
+            None => {
                 tracing::warn!(
-                    "No bindings file found at '{}', using empty structure.",
-                    file_path
+                    "No input id for {}, falling back to port path identity",
+                    full_path
                 );
-                MinimalPathLookup(HashMap::new())
+                minimal_path.clone()
+            }
+        },
+    };
+
+    Ok(Some(NamedPath {
+        full_path: full_path.to_string(),
+        minimal_path,
+        root_event_path: eventpath,
+        common_name: None,
+        identity,
+    }))
+}
+
+fn read_sysfs_attr(root: &Path, event_number: &str, attr: &str) -> Option<String> {
+    let path = root.join(format!(
+        "sys/class/input/event{}/device/{}",
+        event_number, attr
+    ));
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn read_uniq(root: &Path, event_number: &str) -> Option<String> {
+    read_sysfs_attr(root, event_number, "uniq")
+}
+
+/// Builds the same UUID gilrs reports for a device, from the ids the kernel exposes.
+fn read_uuid(root: &Path, event_number: &str) -> Option<String> {
+    let id = |field: &str| {
+        let value = read_sysfs_attr(root, event_number, &format!("id/{}", field))?;
+        u16::from_str_radix(&value, 16).ok()
+    };
+    let bus = id("bustype")? as u32;
+    let vendor = id("vendor")?;
+    let product = id("product")?;
+    let version = id("version")?;
+
+    let mut bytes = Vec::with_capacity(16);
+    bytes.extend_from_slice(&bus.to_le_bytes());
+    bytes.extend_from_slice(&vendor.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(&product.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl MinimalPathLookup {
+    /// Bindings saved before identities existed, or under the port path strategy, are
+    /// moved to the identity of whatever pad is on that port now and written back.
+    pub fn read_from_disk(
+        store: &mut crate::persist::Store,
+        config: &crate::Config,
+    ) -> Result<Self, JoypathsError> {
+        let named_paths = match store.load::<Vec<NamedPath>>()? {
+            Some(named_paths) => named_paths,
+            None => {
+                tracing::warn!("No bindings file found, using empty structure.");
+                return Ok(MinimalPathLookup(HashMap::new()));
+            }
+        };
+
+        let mut migrated = false;
+        let lookup = named_paths
+            .into_iter()
+            .map(|mut np| {
+                if np.identity.is_empty() {
+                    np.identity = np.minimal_path.clone();
+                    migrated = true;
+                }
+                (np.identity.clone(), np)
+            })
+            .collect();
+        let mut lookup = MinimalPathLookup(lookup);
+
+        if config.identity_strategy != IdentityStrategy::PortPath {
+            match repath(config, Path::new(&config.device_root)) {
+                Ok(discovered_paths) => {
+                    for np in discovered_paths {
+                        migrated |= lookup.migrate_port_binding(np);
+                    }
+                }
+                Err(e) => tracing::warn!("Cannot migrate port bindings: {}", e),
             }
         }
+
+        if migrated {
+            lookup.write_to_disk(store)?;
+        }
+        Ok(lookup)
     }
 
-    pub fn write_to_disk(&self, file_path: &str) -> Result<(), serde_json::Error> {
-        let file = File::create(file_path).map_err(serde_json::Error::io)?;
-        let writer = BufWriter::new(file);
-        let named_paths: Vec<&NamedPath> = self.0.values().collect();
-        serde_json::to_writer_pretty(writer, &named_paths)
+    /// Moves a binding keyed by `np`'s port to `np`'s identity. Returns whether it did.
+    fn migrate_port_binding(&mut self, np: NamedPath) -> bool {
+        if np.identity == np.minimal_path || self.0.contains_key(&np.identity) {
+            return false;
+        }
+        let legacy = match self.0.get(&np.minimal_path) {
+            Some(old) => old.identity == old.minimal_path,
+            None => false,
+        };
+        if !legacy {
+            return false;
+        }
+        let old = self.0.remove(&np.minimal_path).unwrap();
+        tracing::info!(
+            "Migrating binding {:?} from {} to {}",
+            old.common_name,
+            old.identity,
+            np.identity
+        );
+        self.0.insert(
+            np.identity.clone(),
+            NamedPath {
+                common_name: old.common_name,
+                ..np
+            },
+        );
+        true
     }
 
-    pub fn add_missing_paths_for_joys(&mut self, config: &crate::Config) {
-        let discovered_paths = repath(config);
+    /// Only touches the file when a binding actually changed.
+    pub fn write_to_disk(&self, store: &mut crate::persist::Store) -> Result<bool, JoypathsError> {
+        // Sorted so an unchanged lookup always serializes the same way
+        let mut named_paths: Vec<&NamedPath> = self.0.values().collect();
+        named_paths.sort_by(|a, b| a.identity.cmp(&b.identity));
+        Ok(store.save(&named_paths)?)
+    }
+
+    pub fn add_missing_paths_for_joys(
+        &mut self,
+        config: &crate::Config,
+    ) -> Result<(), JoypathsError> {
+        let discovered_paths = repath(config, Path::new(&config.device_root))?;
 
-        // Add new paths from discovery if they don't already exist
         for np in discovered_paths {
-            self.0.entry(np.minimal_path.clone()).or_insert(np);
+            if let Some(known) = self.0.get_mut(&np.identity) {
+                // Same pad, possibly on a different port now
+                known.full_path = np.full_path;
+                known.minimal_path = np.minimal_path;
+                known.root_event_path = np.root_event_path;
+                continue;
+            }
+
+            // Add new paths from discovery if they don't already exist
+            self.0.insert(np.identity.clone(), np);
         }
+        Ok(())
     }
 }
 
 impl EventPathLookup {
-    pub fn repath(config: &crate::Config) -> Self {
-        let discovered_paths = repath(config);
+    pub fn repath(config: &crate::Config) -> Result<Self, JoypathsError> {
+        let discovered_paths = repath(config, Path::new(&config.device_root))?;
         let mut lookup = EventPathLookup(HashMap::new());
 
         for np in discovered_paths {
-            lookup.0.insert(np.root_event_path.clone(), np.minimal_path);
+            lookup.0.insert(np.root_event_path.clone(), np.identity);
+        }
+
+        Ok(lookup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    struct FakeDevices {
+        root: TempDir,
+    }
+
+    impl FakeDevices {
+        fn new(name: &str) -> Self {
+            let root = TempDir::new(&format!("joypaths_{}", name));
+            fs::create_dir_all(root.join("dev/input/by-path")).unwrap();
+            FakeDevices { root }
+        }
+
+        fn link_name(port: &str, controller: u32) -> String {
+            format!(
+                "pci-0000:00:14.0-usb-0:{}:1.{}-event-joystick",
+                port, controller
+            )
+        }
+
+        fn plug(&self, port: &str, controller: u32, event: u32) {
+            let link = self
+                .root
+                .join("dev/input/by-path")
+                .join(Self::link_name(port, controller));
+            std::os::unix::fs::symlink(format!("../event{}", event), link).unwrap();
+        }
+
+        fn unplug(&self, port: &str, controller: u32) {
+            let link = self
+                .root
+                .join("dev/input/by-path")
+                .join(Self::link_name(port, controller));
+            fs::remove_file(link).unwrap();
+        }
+
+        fn sysfs(&self, event: u32, attr: &str, value: &str) {
+            let path = self
+                .root
+                .join(format!("sys/class/input/event{}/device", event))
+                .join(attr);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        }
+
+        fn config(&self, multi_port_controllers: u32, extra: &str) -> crate::Config {
+            let json = format!(
+                r#"{{
+                    "path_common_name_max_length": 12,
+                    "hat_only_players": [],
+                    "number_of_multi_port_controllers_to_use": {},
+                    "controller_bindings_file": "bindings.json",
+                    "binding_names_file": "names.txt",
+                    "device_root": {:?}
+                    {}
+                }}"#,
+                multi_port_controllers,
+                self.root.to_str().unwrap(),
+                extra
+            );
+            serde_json::from_str(&json).unwrap()
+        }
+    }
+
+    fn sorted_identities(paths: &[NamedPath]) -> Vec<String> {
+        let mut ids: Vec<String> = paths.iter().map(|np| np.identity.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn discovers_plain_pads() {
+        let devices = FakeDevices::new("plain");
+        devices.plug("1", 0, 5);
+        devices.plug("2", 0, 7);
+        let config = devices.config(1, "");
+
+        let found = repath(&config, &devices.root).unwrap();
+        assert_eq!(sorted_identities(&found), vec!["1:1.0", "2:1.0"]);
+
+        let pad = found.iter().find(|np| np.minimal_path == "2:1.0").unwrap();
+        assert_eq!(
+            pad.root_event_path,
+            devices.root.join("dev/input/event7").to_str().unwrap()
+        );
+        assert_eq!(pad.common_name, None);
+    }
+
+    #[test]
+    fn ignores_non_joysticks_and_bad_links() {
+        let devices = FakeDevices::new("ignores");
+        devices.plug("1", 0, 5);
+        let by_path = devices.root.join("dev/input/by-path");
+        std::os::unix::fs::symlink(
+            "../event9",
+            by_path.join("pci-0000:00:14.0-usb-0:3:1.0-event-kbd"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            "../js0",
+            by_path.join("pci-0000:00:14.0-usb-0:4:1.0-event-joystick"),
+        )
+        .unwrap();
+        let config = devices.config(1, "");
+
+        let found = repath(&config, &devices.root).unwrap();
+        assert_eq!(sorted_identities(&found), vec!["1:1.0"]);
+    }
+
+    #[test]
+    fn multi_port_adapters_respect_limit() {
+        let devices = FakeDevices::new("multiport");
+        for controller in 0..4 {
+            devices.plug("1", controller, 10 + controller);
+        }
+
+        let found = repath(&devices.config(1, ""), &devices.root).unwrap();
+        assert_eq!(sorted_identities(&found), vec!["1:1.0"]);
+
+        let config = devices.config(3, "");
+        let found = repath(&config, &devices.root).unwrap();
+        assert_eq!(sorted_identities(&found), vec!["1:1.0", "1:1.1", "1:1.2"]);
+    }
+
+    #[test]
+    fn missing_device_root_is_an_error() {
+        let devices = FakeDevices::new("missing");
+        let config = devices.config(1, "");
+        let result = repath(&config, &devices.root.join("nope"));
+        assert!(matches!(result, Err(JoypathsError::ReadDir { .. })));
+    }
+
+    #[test]
+    fn device_filter_applies_to_discovery() {
+        let devices = FakeDevices::new("filter");
+        devices.plug("1", 0, 5);
+        devices.sysfs(5, "name", "USB Gamepad\n");
+        devices.plug("2", 0, 6);
+        devices.sysfs(6, "name", "Buster0\n");
+        devices.plug("3", 0, 7);
+        devices.sysfs(7, "name", "Racing Wheel\n");
+        devices.sysfs(7, "id/vendor", "046d\n");
+        let config = devices.config(
+            1,
+            r#", "device_filter": {
+                "deny": [{"name": "Buster*"}, {"vendor": "046d"}]
+            }"#,
+        );
+
+        let found = repath(&config, &devices.root).unwrap();
+        assert_eq!(sorted_identities(&found), vec!["1:1.0"]);
+    }
+
+    #[test]
+    fn hotplug_keeps_bindings() {
+        let devices = FakeDevices::new("hotplug");
+        devices.plug("1", 0, 5);
+        let config = devices.config(1, "");
+
+        let mut mpl = MinimalPathLookup(HashMap::new());
+        mpl.add_missing_paths_for_joys(&config).unwrap();
+        mpl.0.get_mut("1:1.0").unwrap().common_name = Some("Star".to_string());
+
+        devices.plug("2", 0, 6);
+        mpl.add_missing_paths_for_joys(&config).unwrap();
+        let epl = EventPathLookup::repath(&config).unwrap();
+        assert_eq!(mpl.0.len(), 2);
+        assert_eq!(epl.0.len(), 2);
+        assert_eq!(mpl.0["1:1.0"].common_name.as_deref(), Some("Star"));
+
+        devices.unplug("1", 0);
+        mpl.add_missing_paths_for_joys(&config).unwrap();
+        let epl = EventPathLookup::repath(&config).unwrap();
+        assert_eq!(epl.0.len(), 1);
+        assert!(epl.0.values().all(|id| id == "2:1.0"));
+        assert_eq!(mpl.0["1:1.0"].common_name.as_deref(), Some("Star"));
+    }
+
+    #[test]
+    fn uniq_identity_follows_pad_across_ports() {
+        let devices = FakeDevices::new("uniq");
+        devices.plug("1", 0, 5);
+        devices.sysfs(5, "uniq", "aa:bb:cc\n");
+        let config = devices.config(1, r#", "identity_strategy": "uniq""#);
+
+        // Bound before identities existed
+        let bindings = devices.root.join("bindings.json");
+        fs::write(
+            &bindings,
+            r#"[{"full_path": "old", "minimal_path": "1:1.0", "root_event_path": "old",
+                 "common_name": "Star"}]"#,
+        )
+        .unwrap();
+
+        let mut mpl = MinimalPathLookup::read_from_disk(
+            &mut crate::persist::Store::new(&bindings, 0),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(mpl.0.len(), 1);
+        assert_eq!(mpl.0["uniq:aa:bb:cc"].common_name.as_deref(), Some("Star"));
+        let on_disk = fs::read_to_string(&bindings).unwrap();
+        assert!(on_disk.contains(r#""identity": "uniq:aa:bb:cc""#));
+
+        devices.unplug("1", 0);
+        devices.plug("3", 0, 8);
+        devices.sysfs(8, "uniq", "aa:bb:cc\n");
+        mpl.add_missing_paths_for_joys(&config).unwrap();
+        let pad = &mpl.0["uniq:aa:bb:cc"];
+        assert_eq!(pad.common_name.as_deref(), Some("Star"));
+        assert_eq!(pad.minimal_path, "3:1.0");
+    }
+
+    #[test]
+    fn shared_uniq_falls_back_to_port_path() {
+        let devices = FakeDevices::new("uniq_shared");
+        for (port, event) in [("1", 5), ("2", 6), ("3", 7)] {
+            devices.plug(port, 0, event);
+        }
+        devices.sysfs(5, "uniq", "00:00:00\n");
+        devices.sysfs(6, "uniq", "00:00:00\n");
+        devices.sysfs(7, "uniq", "aa:bb:cc\n");
+        let config = devices.config(1, r#", "identity_strategy": "uniq""#);
+
+        let found = repath(&config, &devices.root).unwrap();
+        assert_eq!(
+            sorted_identities(&found),
+            vec!["1:1.0", "2:1.0", "uniq:aa:bb:cc"]
+        );
+    }
+
+    #[test]
+    fn legacy_bindings_upgrade_without_pads() {
+        let devices = FakeDevices::new("legacy");
+        let bindings = devices.root.join("bindings.json");
+        fs::write(
+            &bindings,
+            r#"[{"full_path": "old", "minimal_path": "1:1.0", "root_event_path": "old",
+                 "common_name": "Star"}]"#,
+        )
+        .unwrap();
+
+        let config = devices.config(1, r#", "identity_strategy": "uniq""#);
+        let mpl = MinimalPathLookup::read_from_disk(
+            &mut crate::persist::Store::new(&bindings, 0),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(mpl.0["1:1.0"].identity, "1:1.0");
+        let on_disk: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&bindings).unwrap()).unwrap();
+        assert_eq!(on_disk["data"][0]["identity"], "1:1.0");
+    }
+
+    #[test]
+    fn gilrs_uuid_identity_numbers_identical_pads() {
+        let devices = FakeDevices::new("uuid");
+        for (port, event) in [("1", 5), ("2", 6)] {
+            devices.plug(port, 0, event);
+            devices.sysfs(event, "id/bustype", "0003\n");
+            devices.sysfs(event, "id/vendor", "0079\n");
+            devices.sysfs(event, "id/product", "0011\n");
+            devices.sysfs(event, "id/version", "0110\n");
         }
+        let config = devices.config(1, r#", "identity_strategy": "gilrs_uuid""#);
 
-        lookup
+        let found = repath(&config, &devices.root).unwrap();
+        let uuid = "03000000790000001100000010010000";
+        assert_eq!(
+            sorted_identities(&found),
+            vec![format!("{}#0", uuid), format!("{}#1", uuid)]
+        );
     }
 }
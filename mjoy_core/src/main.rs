@@ -1,8 +1,18 @@
 mod bindings;
+mod calibration;
+mod device_filter;
 mod injoy;
 mod joypaths;
+mod name_pool;
 mod outjoy;
+mod persist;
+mod presence;
+mod presets;
+mod scheduler;
+mod snapshot;
 mod team_select;
+#[cfg(test)]
+mod test_util;
 
 use clap::Parser;
 use command_server;
@@ -11,6 +21,8 @@ use rand;
 use serde::{Deserialize, Serialize};
 use std::cell::{self, RefCell};
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tracing;
 
 #[derive(Parser)]
@@ -25,7 +37,110 @@ pub struct Config {
     hat_only_players: Vec<String>,
     number_of_multi_port_controllers_to_use: u32,
     controller_bindings_file: String,
+    /// Seeds the name pool; names added here later join the pool on the next start
     binding_names_file: String,
+    /// Names in use, retired and who held them last
+    #[serde(default = "default_name_pool_file")]
+    name_pool_file: String,
+    #[serde(default)]
+    identity_strategy: joypaths::IdentityStrategy,
+    /// Prefix for /dev and /sys during controller discovery
+    #[serde(default = "default_device_root")]
+    device_root: String,
+    /// How long an unplugged player keeps their team slot
+    #[serde(default = "default_reconnect_grace_secs")]
+    reconnect_grace_secs: u64,
+    #[serde(default)]
+    device_filter: device_filter::DeviceFilter,
+    /// Rotating backups kept next to bindings.json and teamlock.json
+    #[serde(default = "default_backup_count")]
+    backup_count: usize,
+    #[serde(default = "default_teamlock_file")]
+    teamlock_file: String,
+    #[serde(default = "default_presets_file")]
+    presets_file: String,
+    /// SDL2 mappings recorded by calibration, keyed by gamepad UUID
+    #[serde(default = "default_calibrations_file")]
+    calibrations_file: String,
+    /// Most updates per second sent to each `/events` client
+    #[serde(default = "default_event_stream_hz")]
+    event_stream_hz: f32,
+    /// Cues for a scripted session, run with /schedule/start
+    #[serde(default)]
+    timeline_file: Option<String>,
+    /// Where the command server listens and which tokens it wants
+    #[serde(default)]
+    server: command_server::ServerConfig,
+    /// Also take commands as OSC over UDP, off when missing
+    #[serde(default)]
+    osc: Option<command_server::osc::OscConfig>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+}
+
+fn read_config(path: &str) -> Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_string(),
+        source,
+    })?;
+    serde_json::from_str(&text).map_err(|source| ConfigError::Parse {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Counts a controller discovery pass for `/metrics` and passes its result on.
+fn count_repath<T, E>(
+    metrics: &command_server::metrics::Metrics,
+    result: Result<T, E>,
+) -> Result<T, E> {
+    let label = if result.is_ok() { "ok" } else { "error" };
+    metrics.inc("mjoy_repaths_total", &[("result", label)]);
+    result
+}
+
+fn default_device_root() -> String {
+    "/".to_string()
+}
+
+fn default_reconnect_grace_secs() -> u64 {
+    30
+}
+
+fn default_backup_count() -> usize {
+    3
+}
+
+fn default_teamlock_file() -> String {
+    "teamlock.json".to_string()
+}
+
+fn default_presets_file() -> String {
+    "presets.json".to_string()
+}
+
+fn default_name_pool_file() -> String {
+    "names.json".to_string()
+}
+
+fn default_calibrations_file() -> String {
+    "calibrations.json".to_string()
+}
+
+fn default_event_stream_hz() -> f32 {
+    10.0
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -35,22 +150,71 @@ pub struct Team {
     out_index: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TeamLock {
     teams: Vec<Team>,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum GameState {
     Binding,
+    Identify,
+    Calibrating,
     TeamSelect,
     GameActive,
+    /// Outputs released and frozen until Resume
+    Paused,
+}
+
+impl GameState {
+    /// Whether a command may take the game from here to `to`. A running game has to
+    /// go back to team select first, and only Resume leaves a pause, which doesn't go
+    /// through here.
+    fn allows(self, to: GameState) -> Result<(), command_server::Refusal> {
+        use GameState::*;
+        let allowed = match (self, to) {
+            (Paused, _) => false,
+            (_, TeamSelect) => true,
+            (TeamSelect | GameActive, GameActive) => true,
+            (GameActive, Paused) => true,
+            (GameActive, _) => false,
+            (_, GameActive | Paused) => false,
+            _ => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(command_server::Refusal::Conflict(format!(
+                "can't go from {:?} to {:?}",
+                self, to
+            )))
+        }
+    }
 }
 
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
-fn main() {
+/// Exit codes, so whatever restarts us can tell a requested stop from trouble.
+#[derive(Debug, Clone, Copy)]
+enum Exit {
+    /// Shutdown from the command server
+    Requested = 0,
+    /// Bindings, teams or names could not be saved on the way out
+    FlushFailed = 1,
+    /// SIGINT, SIGTERM or SIGHUP. ctrlc doesn't say which, so all of them report
+    /// 128 + SIGINT, as a shell would for Ctrl-C
+    Signalled = 130,
+}
+
+impl From<Exit> for std::process::ExitCode {
+    fn from(exit: Exit) -> Self {
+        std::process::ExitCode::from(exit as u8)
+    }
+}
+
+fn main() -> std::process::ExitCode {
     // Initialize the subscriber to listen to all logs with DEBUG level and above
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::DEBUG) // Explicitly set to DEBUG level
@@ -60,16 +224,90 @@ fn main() {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
     let args = Cli::parse();
-    let config = serde_json::from_str::<Config>(&std::fs::read_to_string(&args.config).unwrap())
-        .expect("Failed to parse config file");
+    let mut config = read_config(&args.config).expect("Failed to load config file");
     dbg!(&config);
 
     let (tx, rx) = std::sync::mpsc::channel();
-    let server_handle = std::thread::spawn(|| command_server::field_commands_forever(tx));
+    let shared_snapshot = command_server::state::SharedSnapshot::default();
+    let server_snapshot = shared_snapshot.clone();
+    let event_hub = Arc::new(command_server::events::EventHub::new(
+        config.event_stream_hz,
+    ));
+    let server_events = event_hub.clone();
+    let server_config = config.server.clone();
+    let metrics = Arc::new(command_server::metrics::Metrics::default());
+    let server_metrics = metrics.clone();
+    let server_stop = command_server::StopSignal::default();
+    let listener_stop = server_stop.clone();
+    if let Some(osc_config) = config.osc.clone() {
+        let osc_sender = tx.clone();
+        let osc_stop = server_stop.clone();
+        let osc_server_config = config.server.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = command_server::osc::field_osc_forever(
+                osc_config,
+                &osc_server_config,
+                osc_sender,
+                osc_stop,
+            ) {
+                tracing::error!("OSC listener failed: {}", e);
+            }
+        });
+    }
+    // Its own thread, so the socket is up even when no web listener can be bound
+    let socket_handle = {
+        let (sender, snapshot, events) =
+            (tx.clone(), server_snapshot.clone(), server_events.clone());
+        let (config, metrics, stop) = (
+            server_config.clone(),
+            server_metrics.clone(),
+            server_stop.clone(),
+        );
+        std::thread::spawn(move || {
+            if let Err(e) = command_server::field_socket_forever(
+                sender, snapshot, events, config, metrics, stop,
+            ) {
+                tracing::error!("Control socket failed: {}", e);
+            }
+        })
+    };
+    let server_handle = std::thread::spawn(|| {
+        // The game runs on without the server, e.g. when its port is taken
+        if let Err(e) = command_server::field_commands_forever(
+            tx,
+            server_snapshot,
+            server_events,
+            server_config,
+            server_metrics,
+            listener_stop,
+        ) {
+            tracing::error!("Command server failed: {}", e);
+        }
+    });
+
+    let signalled = Arc::new(AtomicBool::new(false));
+    {
+        let signalled = signalled.clone();
+        ctrlc::set_handler(move || {
+            // A second signal means the clean shutdown is stuck
+            if signalled.swap(true, Ordering::SeqCst) {
+                std::process::exit(Exit::Signalled as i32);
+            }
+        })
+        .expect("Failed to install signal handler");
+    }
 
     // Read configuration file .json file
-    let mut mpl = joypaths::MinimalPathLookup::read_from_disk(&config.controller_bindings_file);
-    mpl.add_missing_paths_for_joys(&config);
+    let mut bindings_store =
+        persist::Store::new(&config.controller_bindings_file, config.backup_count);
+    let mut mpl = joypaths::MinimalPathLookup::read_from_disk(&mut bindings_store, &config)
+        .unwrap_or_else(|e| {
+            tracing::error!("{}, starting without bindings", e);
+            joypaths::MinimalPathLookup(std::collections::HashMap::new())
+        });
+    if let Err(e) = count_repath(&metrics, mpl.add_missing_paths_for_joys(&config)) {
+        tracing::warn!("Controller discovery failed: {}", e);
+    }
 
     let mut minimal_paths: Vec<&String> = mpl.0.keys().collect();
     minimal_paths.sort();
@@ -85,41 +323,41 @@ fn main() {
         println!("{: <15} -> {: <20}", name, path);
     }
 
-    let frozen_path = "teamlock.json";
+    let mut name_pool = name_pool::NamePool::new(persist::Store::new(
+        &config.name_pool_file,
+        config.backup_count,
+    ));
+    if let Err(e) = name_pool.load(&config.binding_names_file) {
+        // Whatever was read before the failure is kept
+        tracing::error!(
+            "{}, starting with {} names",
+            e,
+            name_pool.status(&mpl).len()
+        );
+    }
+    if let Err(e) = name_pool.record(&mpl) {
+        tracing::error!("Failed to save name pool: {}", e);
+    }
+
+    let mut teamlock_store = persist::Store::new(&config.teamlock_file, config.backup_count);
+    let mut presets = presets::Presets::new(persist::Store::new(
+        &config.presets_file,
+        config.backup_count,
+    ));
+    if let Err(e) = presets.load() {
+        tracing::error!("{}, starting without presets", e);
+    }
     // Check for a teamlock.json file
-    let mut frozen = if std::path::Path::new(&frozen_path).exists() {
+    let frozen = teamlock_store.load::<TeamLock>().unwrap_or_else(|e| {
+        tracing::error!("{}, starting with the default teams", e);
+        None
+    });
+    let mut frozen = if let Some(frozen) = frozen {
         // If it exists, read it and return it
-        let frozen =
-            serde_json::from_str::<TeamLock>(&std::fs::read_to_string(&frozen_path).unwrap())
-                .expect("Failed to parse frozen file");
         frozen
     } else {
-        let team0 = Team {
-            name: "Elemental Moose".to_owned(),
-            players: vec![],
-            out_index: 0,
-        };
-        let team1 = {
-            let mut t = team0.clone();
-            t.name = "Lucky Bulldogs".to_owned();
-            t.out_index = 1;
-            t
-        };
-        let team2 = {
-            let mut t = team0.clone();
-            t.name = "Orange Dragons".to_owned();
-            t.out_index = 2;
-            t
-        };
-        let team3 = {
-            let mut t = team0.clone();
-            t.name = "Stubborn TrashPandas".to_owned();
-            t.out_index = 3;
-            t
-        };
-        let tl = TeamLock {
-            teams: vec![team0, team1, team2, team3],
-        };
+        let mut tl = TeamLock { teams: vec![] };
+        team_select::resize(&mut tl, 4).expect("4 teams are allowed");
         tl
     };
     dbg!(&frozen);
@@ -143,6 +381,8 @@ fn main() {
         }
     }
 
+    // Players without any binding can never reconnect. Bound players whose pads are
+    // unplugged get the reconnect grace period instead, see `presence`.
     if missing_players.len() > 0 {
         println!("Missing players:");
         tracing::warn!("Missing players. Removing: {:?}", missing_players);
@@ -153,12 +393,27 @@ fn main() {
         }
     }
 
-    let frozen_json = serde_json::to_string_pretty(&frozen).unwrap();
-    std::fs::write(frozen_path, frozen_json).unwrap();
+    if let Err(e) = teamlock_store.save(&frozen) {
+        tracing::error!("Failed to save teams: {}", e);
+    }
 
     use gilrs;
 
-    let mut gilrs = gilrs::Gilrs::new().unwrap();
+    let mut calibrations = calibration::Calibrations::new(persist::Store::new(
+        &config.calibrations_file,
+        config.backup_count,
+    ));
+    if let Err(e) = calibrations.load() {
+        tracing::error!("{}, starting without calibrations", e);
+    }
+    // gilrs would add its own mappings over ours, so they all go in here in order
+    let env_mappings = std::env::var("SDL_GAMECONTROLLERCONFIG").ok();
+    let mut gilrs = gilrs::GilrsBuilder::new()
+        .add_included_mappings(false)
+        .add_env_mappings(false)
+        .add_mappings(&calibrations.builder_mappings(env_mappings.as_deref()))
+        .build()
+        .unwrap();
 
     // Iterate over all connected gamepads
     for (_id, gamepad) in gilrs.gamepads() {
@@ -169,7 +424,27 @@ fn main() {
             gamepad.devpath()
         );
     }
-    let mut event_path_lookup = joypaths::EventPathLookup::repath(&config);
+    metrics.set(
+        "mjoy_devices_connected",
+        &[],
+        gilrs.gamepads().count() as f64,
+    );
+    let mut event_path_lookup = count_repath(&metrics, joypaths::EventPathLookup::repath(&config))
+        .unwrap_or_else(|e| {
+            tracing::warn!("Controller discovery failed: {}", e);
+            joypaths::EventPathLookup(std::collections::HashMap::new())
+        });
+    config
+        .device_filter
+        .prune(&gilrs, &mut event_path_lookup, &mpl);
+
+    let mut reconnect_grace = std::time::Duration::from_secs(config.reconnect_grace_secs);
+    let mut presence = presence::Presence::new();
+    presence.update(
+        &frozen,
+        &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+        std::time::Instant::now(),
+    );
 
     let mut gui_teams = Vec::new();
     for team in frozen.teams.iter() {
@@ -187,46 +462,430 @@ fn main() {
     }
 
     let mut top_context = RefCell::new(Some(TopContext {
-        fbinfo: update_gui_teams(&frozen),
+        fbinfo: update_gui_teams(&frozen, &presence),
         all_joys: outjoy::Outjoys::new(&frozen),
     }));
 
     let mut thresh = 0.9f32;
+    let mut threshold_profile = command_server::ThresholdProfile::default();
+    let mut aggregation = command_server::AggregationMode::default();
+    let mut paused_from = GameState::TeamSelect;
     let mut change_thresh_time = std::time::Instant::now() + std::time::Duration::from_secs(1);
     let mut gui_render_time = std::time::Instant::now();
     let mut game_state: GameState = GameState::TeamSelect;
-    let mut binder = crate::bindings::Binder::new(config.binding_names_file.clone());
+    let mut schedule = scheduler::Scheduler::default();
+    // Due cues wait here and are handled one per loop, like commands from the server
+    let mut cued = std::collections::VecDeque::new();
+    let mut binder = crate::bindings::Binder::new();
+    let mut gestures = bindings::Gestures::default();
+    let mut calibrator = calibration::Calibrator::new();
     let mut candidate = None;
-    loop {
+    let mut rate_since = std::time::Instant::now();
+    let mut rate_iterations = 0u32;
+    let exit = loop {
+        if signalled.load(Ordering::SeqCst) {
+            break Exit::Signalled;
+        }
+
+        metrics.inc("mjoy_loop_iterations_total", &[]);
+        rate_iterations += 1;
+        let rate_elapsed = rate_since.elapsed();
+        if rate_elapsed >= std::time::Duration::from_secs(1) {
+            metrics.set(
+                "mjoy_loop_rate_hz",
+                &[],
+                f64::from(rate_iterations) / rate_elapsed.as_secs_f64(),
+            );
+            rate_since = std::time::Instant::now();
+            rate_iterations = 0;
+        }
+
         let event = gilrs.next_event();
 
         match &event {
             Some(gilrs::Event {
-                event: gilrs::EventType::Connected | gilrs::EventType::Disconnected,
+                event: device_event @ (gilrs::EventType::Connected | gilrs::EventType::Disconnected),
                 ..
             }) => {
-                mpl.add_missing_paths_for_joys(&config);
-                event_path_lookup = joypaths::EventPathLookup::repath(&config);
+                let label = match device_event {
+                    gilrs::EventType::Connected => "connected",
+                    _ => "disconnected",
+                };
+                metrics.inc("mjoy_device_events_total", &[("event", label)]);
+                metrics.set(
+                    "mjoy_devices_connected",
+                    &[],
+                    gilrs.gamepads().count() as f64,
+                );
+
+                // Keep the previous lookups if discovery fails mid-hotplug
+                if let Err(e) = count_repath(&metrics, mpl.add_missing_paths_for_joys(&config)) {
+                    tracing::warn!("Controller discovery failed: {}", e);
+                }
+                match count_repath(&metrics, joypaths::EventPathLookup::repath(&config)) {
+                    Ok(epl) => event_path_lookup = epl,
+                    Err(e) => tracing::warn!("Controller discovery failed: {}", e),
+                }
+                config
+                    .device_filter
+                    .prune(&gilrs, &mut event_path_lookup, &mpl);
                 dbg!((&mpl, &event_path_lookup));
+
+                presence.update(
+                    &frozen,
+                    &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+                    std::time::Instant::now(),
+                );
+                if let Some(tc) = top_context.borrow_mut().as_mut() {
+                    presence.mark_feedback(&mut tc.fbinfo);
+                }
                 continue;
             }
             _ => {}
         }
 
+        // Calibration needs the raw code of each press, which only events carry
+        if let (GameState::Calibrating, Some(ev)) = (&game_state, &event) {
+            use calibration::CalibrationState;
+            match calibrator.handle_event(&mut gilrs, ev) {
+                CalibrationState::Prompt(prompt) => candidate = Some(prompt),
+                CalibrationState::Done(uuid, sdl_mapping) => {
+                    tracing::info!("Calibrated {}: {}", uuid, sdl_mapping);
+                    if let Err(e) = calibrations.save(&uuid, &sdl_mapping) {
+                        tracing::error!("Failed to save calibration: {}", e);
+                    }
+                    candidate = None;
+                    game_state = GameState::TeamSelect;
+                }
+                CalibrationState::Failed(message) => {
+                    tracing::error!("{}", message);
+                    candidate = None;
+                    game_state = GameState::TeamSelect;
+                }
+            }
+        }
+
         // Have gilrs process all events so the cached state is as up to date as possible
         if event.is_some() {
             continue;
         }
 
-        use command_server::Command;
-        match rx.try_recv() {
-            Ok(Command::Setup) => {
-                binder = crate::bindings::Binder::new(config.binding_names_file.clone());
-                game_state = GameState::Binding
+        let mut teams_changed = false;
+
+        use command_server::{Command, Envelope, Refusal};
+        // Outside a game the pads can identify themselves and rebind their own name,
+        // the same as asking the server. Binding has its own use for their buttons.
+        let gesture = gestures.poll(&gilrs, &event_path_lookup);
+        let mut asking_pad = None;
+        let gesture = match (gesture, game_state) {
+            (
+                _,
+                GameState::GameActive
+                | GameState::Paused
+                | GameState::Calibrating
+                | GameState::Binding,
+            ) => None,
+            (Some(bindings::Gesture::Identify), GameState::Identify) => Some(Command::Teams(None)),
+            (Some(bindings::Gesture::Identify), _) => Some(Command::Identify),
+            (Some(bindings::Gesture::RebindOwn(identity)), _) => {
+                match mpl.0.get(&identity).and_then(|np| np.common_name.clone()) {
+                    Some(name) => {
+                        asking_pad = Some(identity);
+                        Some(Command::RebindOne(name))
+                    }
+                    None => {
+                        tracing::info!("{} has no name to rebind yet", identity);
+                        None
+                    }
+                }
+            }
+            (None, _) => None,
+        };
+        cued.extend(schedule.due(std::time::Instant::now()));
+        // Nobody waits on a pad's or a cue's answer, refusals are only logged
+        let unanswered = |source, command| {
+            Some((
+                source,
+                Envelope {
+                    command,
+                    reply: std::sync::mpsc::channel().0,
+                },
+            ))
+        };
+        let next_command = if let Some(command) = gesture {
+            unanswered("pad", command)
+        } else if let Some(command) = cued.pop_front() {
+            unanswered("schedule", command)
+        } else {
+            rx.try_recv().ok().map(|envelope| ("server", envelope))
+        };
+        if let Some((source, Envelope { command, reply })) = next_command {
+            let command_name = command.name();
+            let mut shutdown = None;
+            // Queries answer with their result, everything else with None
+            let outcome: Result<Option<serde_json::Value>, Refusal> = match command {
+                Command::Setup => game_state.allows(GameState::Binding).map(|()| {
+                    binder = crate::bindings::Binder::rebind_everyone(&mpl);
+                    game_state = GameState::Binding;
+                    None
+                }),
+                Command::Teams(count) => game_state.allows(GameState::TeamSelect).and_then(|()| {
+                    if let Some(count) = count {
+                        team_select::resize(&mut frozen, count)?;
+                        presence.update(
+                            &frozen,
+                            &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+                            std::time::Instant::now(),
+                        );
+                        teams_changed = true;
+                    }
+                    game_state = GameState::TeamSelect;
+                    Ok(None)
+                }),
+                Command::Start => game_state.allows(GameState::GameActive).map(|()| {
+                    // Team select may still undo the last binding session, a game may not
+                    binder = bindings::Binder::new();
+                    game_state = GameState::GameActive;
+                    None
+                }),
+                Command::SavePreset(name) => match presets.save(&name, &frozen) {
+                    Ok(()) => {
+                        tracing::info!("Saved preset {}", name);
+                        Ok(None)
+                    }
+                    Err(e) => Err(Refusal::Failed(format!(
+                        "failed to save preset {}: {}",
+                        name, e
+                    ))),
+                },
+                Command::LoadPreset(name) => match presets.get(&name) {
+                    Some(preset) => {
+                        tracing::info!("Loaded preset {}", name);
+                        frozen = preset.clone();
+                        presence.update(
+                            &frozen,
+                            &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+                            std::time::Instant::now(),
+                        );
+                        teams_changed = true;
+                        Ok(None)
+                    }
+                    None => Err(Refusal::NotFound(format!("no preset named {}", name))),
+                },
+                Command::ListPresets => Ok(Some(serde_json::json!(presets.names()))),
+                Command::RebindOne(name) => game_state.allows(GameState::Binding).map(|()| {
+                    // Gestures go first, so an asking pad is the one this came from
+                    binder = bindings::Binder::rebind_one(name, asking_pad.take());
+                    game_state = GameState::Binding;
+                    None
+                }),
+                Command::UndoBinding => game_state.allows(GameState::Binding).and_then(|()| {
+                    match binder.undo(&mut mpl) {
+                        Some(_) => {
+                            game_state = GameState::Binding;
+                            Ok(None)
+                        }
+                        None => Err(Refusal::Conflict("nothing to undo".to_string())),
+                    }
+                }),
+                Command::Identify => game_state.allows(GameState::Identify).map(|()| {
+                    candidate = Some("Press any button to see your name".to_string());
+                    game_state = GameState::Identify;
+                    None
+                }),
+                Command::Calibrate => game_state.allows(GameState::Calibrating).map(|()| {
+                    calibrator = calibration::Calibrator::new();
+                    candidate = Some(calibrator.prompt());
+                    game_state = GameState::Calibrating;
+                    None
+                }),
+                Command::ExportNames => Ok(Some(name_pool.export(&mpl))),
+                Command::ImportNames(json) => match name_pool.import(&json) {
+                    Ok(count) => {
+                        tracing::info!("Imported {} names", count);
+                        Ok(Some(serde_json::json!({ "imported": count })))
+                    }
+                    Err(e @ name_pool::NamePoolError::Import(_)) => {
+                        Err(Refusal::Invalid(e.to_string()))
+                    }
+                    Err(e) => Err(Refusal::Failed(e.to_string())),
+                },
+                Command::RetireName(name) => match name_pool.set_retired(&name, true) {
+                    Ok(true) => {
+                        tracing::info!("Retired {}", name);
+                        Ok(None)
+                    }
+                    Ok(false) => Err(Refusal::NotFound(format!("no name {} in the pool", name))),
+                    Err(e) => Err(Refusal::Failed(format!("failed to save name pool: {}", e))),
+                },
+                Command::RestoreName(name) => match name_pool.set_retired(&name, false) {
+                    Ok(true) => {
+                        tracing::info!("Restored {}", name);
+                        Ok(None)
+                    }
+                    Ok(false) => Err(Refusal::NotFound(format!("no name {} in the pool", name))),
+                    Err(e) => Err(Refusal::Failed(format!("failed to save name pool: {}", e))),
+                },
+                Command::ListUnbound => Ok(Some(serde_json::json!(bindings::unbound_pads(
+                    &gilrs,
+                    &event_path_lookup,
+                    &mpl
+                )))),
+                Command::Pause => game_state.allows(GameState::Paused).map(|()| {
+                    if let Some(tc) = top_context.borrow().as_ref() {
+                        tc.all_joys.release();
+                    }
+                    paused_from = game_state;
+                    candidate = Some("Paused".to_string());
+                    game_state = GameState::Paused;
+                    None
+                }),
+                Command::Resume if game_state == GameState::Paused => {
+                    candidate = None;
+                    game_state = paused_from;
+                    Ok(None)
+                }
+                Command::Resume => Err(Refusal::Conflict("the game is not paused".to_string())),
+                Command::MovePlayer { player, team } => {
+                    team_select::move_player(&mut frozen, &player, &team)
+                        .map(|()| {
+                            tracing::info!("Moved {} to {}", player, team);
+                            presence.update(
+                                &frozen,
+                                &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+                                std::time::Instant::now(),
+                            );
+                            teams_changed = true;
+                            None
+                        })
+                        .map_err(Refusal::from)
+                }
+                Command::RenameTeam { team, name } => {
+                    team_select::rename_team(&mut frozen, &team, &name)
+                        .map(|()| {
+                            tracing::info!("Renamed {} to {}", team, name);
+                            teams_changed = true;
+                            None
+                        })
+                        .map_err(Refusal::from)
+                }
+                Command::SetThreshold(profile) => {
+                    threshold_profile = profile;
+                    // A new drift range takes effect straight away
+                    change_thresh_time = std::time::Instant::now();
+                    Ok(None)
+                }
+                Command::SetAggregationMode(mode) => {
+                    aggregation = mode;
+                    Ok(None)
+                }
+                Command::ReloadConfig => match read_config(&args.config) {
+                    // Bindings are keyed by identity, which these decide
+                    Ok(reloaded)
+                        if reloaded.identity_strategy != config.identity_strategy
+                            || reloaded.device_root != config.device_root =>
+                    {
+                        Err(Refusal::Conflict(
+                            "identity_strategy and device_root only change on restart".to_string(),
+                        ))
+                    }
+                    Ok(reloaded) => {
+                        // Files, the server and the event rate are only read at startup
+                        config = reloaded;
+                        reconnect_grace =
+                            std::time::Duration::from_secs(config.reconnect_grace_secs);
+                        match count_repath(&metrics, joypaths::EventPathLookup::repath(&config)) {
+                            Ok(epl) => event_path_lookup = epl,
+                            Err(e) => tracing::warn!("Controller discovery failed: {}", e),
+                        }
+                        config
+                            .device_filter
+                            .prune(&gilrs, &mut event_path_lookup, &mpl);
+                        presence.update(
+                            &frozen,
+                            &presence::connected_players(&gilrs, &event_path_lookup, &mpl),
+                            std::time::Instant::now(),
+                        );
+                        tracing::info!("Reloaded {}", args.config);
+                        Ok(None)
+                    }
+                    Err(e) => Err(Refusal::Failed(format!(
+                        "{}, keeping the current config",
+                        e
+                    ))),
+                },
+                Command::StartSchedule => match config.timeline_file.as_deref() {
+                    Some(path) => match scheduler::Scheduler::load(path) {
+                        Ok(loaded) => {
+                            tracing::info!("Running timeline {}", path);
+                            schedule = loaded;
+                            schedule.start(std::time::Instant::now());
+                            cued.clear();
+                            Ok(None)
+                        }
+                        Err(e) => Err(Refusal::Failed(e.to_string())),
+                    },
+                    None => Err(Refusal::NotFound(
+                        "no timeline_file in the config".to_string(),
+                    )),
+                },
+                Command::PauseSchedule => match schedule.pause(std::time::Instant::now()) {
+                    true => Ok(None),
+                    false => Err(Refusal::Conflict("the schedule is not running".to_string())),
+                },
+                Command::ResumeSchedule => match schedule.resume(std::time::Instant::now()) {
+                    true => Ok(None),
+                    false => Err(Refusal::Conflict("the schedule is not paused".to_string())),
+                },
+                Command::Shutdown => {
+                    // The caller may stop waiting once answered, so the pads are let go
+                    // and everything is on disk first
+                    top_context.replace(None);
+                    let flushed = flush(
+                        &mpl,
+                        &mut bindings_store,
+                        &mut teamlock_store,
+                        &frozen,
+                        &mut name_pool,
+                    );
+                    if flushed {
+                        shutdown = Some(Exit::Requested);
+                        Ok(None)
+                    } else {
+                        shutdown = Some(Exit::FlushFailed);
+                        Err(Refusal::Failed(
+                            "shutting down, but not everything could be saved".to_string(),
+                        ))
+                    }
+                }
+            };
+            if let Err(refusal) = &outcome {
+                tracing::warn!("Command refused: {}", refusal);
+            }
+            let result = if outcome.is_ok() { "ok" } else { "refused" };
+            metrics.inc(
+                "mjoy_commands_total",
+                &[
+                    ("command", command_name),
+                    ("source", source),
+                    ("outcome", result),
+                ],
+            );
+            let _ = reply.send(outcome.map(|answer| {
+                answer.unwrap_or_else(|| serde_json::json!({ "game_state": game_state }))
+            }));
+            if let Some(exit) = shutdown {
+                break exit;
+            }
+        }
+
+        let expired = presence.expire(std::time::Instant::now(), reconnect_grace);
+        if !expired.is_empty() {
+            tracing::warn!("Players did not reconnect in time. Removing: {:?}", expired);
+            for team in frozen.teams.iter_mut() {
+                team.players.retain(|player| !expired.contains(player));
             }
-            Ok(Command::Teams(_)) => game_state = GameState::TeamSelect,
-            Ok(Command::Start) => game_state = GameState::GameActive,
-            Err(_) => (),
+            teams_changed = true;
         }
 
         match game_state {
@@ -245,10 +904,14 @@ fn main() {
                     feedback: &mut fbinfo,
                     hat_only_player_names: &config.hat_only_players,
                     button_threshold: thresh,
+                    aggregation,
+                    metrics: &metrics,
                 });
 
                 let now = std::time::Instant::now();
-                if now.checked_duration_since(change_thresh_time).is_some() {
+                if let command_server::ThresholdProfile::Fixed(fixed) = threshold_profile {
+                    thresh = fixed;
+                } else if now.checked_duration_since(change_thresh_time).is_some() {
                     change_thresh_time = change_thresh_time + {
                         // Random number up to 5000
                         let random_millis = rand::random::<u64>() % 5000;
@@ -258,31 +921,51 @@ fn main() {
                     if now < change_thresh_time {
                         change_thresh_time = now + std::time::Duration::from_secs(1);
                     }
-                    thresh = {
-                        let rand = rand::random::<u64>();
-                        let rand = rand % 10000;
-                        let rand = rand as f32;
-                        let rand = rand / 10000.0;
-                        let mut rand = rand * 0.61;
-                        rand += 0.49;
-                        rand.min(0.95f32)
-                    };
+                    if let command_server::ThresholdProfile::Drift { min, max } = threshold_profile
+                    {
+                        thresh = {
+                            let rand = rand::random::<u64>();
+                            let rand = rand % 10000;
+                            let rand = rand as f32;
+                            let rand = rand / 10000.0;
+                            let mut rand = rand * (max - min);
+                            rand += min;
+                            rand.min(0.95f32)
+                        };
+                    }
                 }
 
                 top_context.replace(Some(TopContext { fbinfo, all_joys }));
             }
             GameState::Binding => {
-                let result = binder.update(&gilrs, &event_path_lookup, &mut mpl);
-                mpl.write_to_disk(&config.controller_bindings_file);
+                let result = binder.update(&gilrs, &event_path_lookup, &mut mpl, &name_pool);
+                match mpl.write_to_disk(&mut bindings_store) {
+                    Ok(true) => {
+                        if let Err(e) = name_pool.record(&mpl) {
+                            tracing::error!("Failed to save name pool: {}", e);
+                        }
+                    }
+                    Ok(false) => (),
+                    Err(e) => tracing::error!("Failed to save bindings: {}", e),
+                }
                 use bindings::UpdateState;
                 match result {
                     UpdateState::Done => {
                         candidate = None;
                         game_state = GameState::TeamSelect
                     }
-                    UpdateState::Binding(cand) => candidate = cand,
+                    UpdateState::Binding(cand) => {
+                        let waiting = binder.waiting_pads(&gilrs, &event_path_lookup, &mpl).len();
+                        candidate = cand.map(|c| format!("{} ({} pads waiting)", c, waiting))
+                    }
+                }
+            }
+            GameState::Identify => {
+                if let Some(name) = bindings::identify(&gilrs, &event_path_lookup, &mpl) {
+                    candidate = Some(name);
                 }
             }
+            GameState::Calibrating | GameState::Paused => (),
             GameState::TeamSelect => {
                 candidate = None;
 
@@ -292,43 +975,128 @@ fn main() {
                     &mpl,
                     &mut gilrs,
                 );
-                if changed {
-                    let TopContext {
-                        mut fbinfo,
-                        mut all_joys,
-                    } = top_context.replace(None).unwrap();
-
-                    let fbinfo = update_gui_teams(&frozen);
-                    let all_joys = {
-                        all_joys.overwrite(&frozen);
-                        all_joys
-                    };
-                    let new_context = TopContext { fbinfo, all_joys };
-                    top_context.replace(Some(new_context));
-
-                    let frozen_json = serde_json::to_string_pretty(&frozen).unwrap();
-                    std::fs::write(frozen_path, frozen_json).unwrap();
-                }
+                teams_changed = teams_changed || changed;
             }
         };
 
+        if teams_changed {
+            let TopContext {
+                mut fbinfo,
+                mut all_joys,
+            } = top_context.replace(None).unwrap();
+
+            let fbinfo = update_gui_teams(&frozen, &presence);
+            let team_names: Vec<String> = frozen.teams.iter().map(|t| t.name.clone()).collect();
+            if ui.teams() != team_names.as_slice() {
+                ui.set_teams(&team_names);
+            }
+            let all_joys = {
+                all_joys.overwrite(&frozen);
+                all_joys
+            };
+            let new_context = TopContext { fbinfo, all_joys };
+            top_context.replace(Some(new_context));
+
+            if let Err(e) = teamlock_store.save(&frozen) {
+                tracing::error!("Failed to save teams: {}", e);
+            }
+        }
+
         if std::time::Instant::now()
             .checked_duration_since(gui_render_time)
             .is_some()
         {
             gui_render_time = std::time::Instant::now() + std::time::Duration::from_millis(50);
+            let published = snapshot::SnapshotContext {
+                game_state: &game_state,
+                threshold: thresh,
+                aggregation,
+                prompt: &candidate,
+                teams: &frozen,
+                presence: &presence,
+                minimal_path_lookup: &mpl,
+                event_path_lookup: &event_path_lookup,
+                gilrs: &gilrs,
+                schedule: &schedule,
+            };
+            published.publish(&shared_snapshot);
+            published.offer_events(
+                &event_hub,
+                top_context.borrow().as_ref().map(|tc| &tc.fbinfo),
+            );
+            ui.set_cues(schedule.display(std::time::Instant::now()));
             if let Some(tc) = top_context.borrow().as_ref() {
-                ui.render(&tc.fbinfo, game_state == GameState::GameActive, candidate);
+                ui.render(
+                    &tc.fbinfo,
+                    game_state == GameState::GameActive,
+                    candidate.clone(),
+                );
             } else {
                 tracing::error!("No update");
             }
         } else {
             continue;
         }
+    };
+
+    // A requested shutdown was flushed before it was answered
+    let exit = match exit {
+        Exit::Signalled => {
+            // Dropping the virtual pads lets go of everything they held
+            top_context.replace(None);
+            match flush(
+                &mpl,
+                &mut bindings_store,
+                &mut teamlock_store,
+                &frozen,
+                &mut name_pool,
+            ) {
+                true => exit,
+                false => Exit::FlushFailed,
+            }
+        }
+        exit => exit,
+    };
+
+    server_stop.stop();
+    if server_handle.join().is_err() {
+        tracing::error!("Command server panicked");
+    }
+    if socket_handle.join().is_err() {
+        tracing::error!("Control socket panicked");
     }
+    tracing::info!("Shut down: {:?}", exit);
+    exit.into()
 }
 
-fn update_gui_teams(frozen: &TeamLock) -> mjoy_gui::gui::feedback_info::FeedbackInfo {
+/// Saves bindings, teams and names on the way out. Returns false if any of them failed.
+fn flush(
+    mpl: &joypaths::MinimalPathLookup,
+    bindings_store: &mut persist::Store,
+    teamlock_store: &mut persist::Store,
+    frozen: &TeamLock,
+    name_pool: &mut name_pool::NamePool,
+) -> bool {
+    let mut flushed = true;
+    if let Err(e) = mpl.write_to_disk(bindings_store) {
+        tracing::error!("Failed to save bindings: {}", e);
+        flushed = false;
+    }
+    if let Err(e) = teamlock_store.save(frozen) {
+        tracing::error!("Failed to save teams: {}", e);
+        flushed = false;
+    }
+    if let Err(e) = name_pool.record(mpl) {
+        tracing::error!("Failed to save name pool: {}", e);
+        flushed = false;
+    }
+    flushed
+}
+
+fn update_gui_teams(
+    frozen: &TeamLock,
+    presence: &presence::Presence,
+) -> mjoy_gui::gui::feedback_info::FeedbackInfo {
     let feedback = {
         let mut fb = Vec::new();
 
@@ -349,6 +1117,7 @@ fn update_gui_teams(frozen: &TeamLock) -> mjoy_gui::gui::feedback_info::Feedback
             let fbplayer = mjoy_gui::gui::feedback_info::Player {
                 player_name: player.clone(),
                 feedback: feedback.clone(),
+                connected: presence.is_connected(player),
             };
             fbplayers.push(fbplayer);
         }
@@ -365,3 +1134,23 @@ fn update_gui_teams(frozen: &TeamLock) -> mjoy_gui::gui::feedback_info::Feedback
 
     fbinfo
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        assert!(GameState::TeamSelect.allows(GameState::GameActive).is_ok());
+        assert!(GameState::Binding.allows(GameState::GameActive).is_err());
+        assert!(GameState::GameActive.allows(GameState::Binding).is_err());
+        assert!(GameState::GameActive.allows(GameState::Paused).is_ok());
+        assert!(GameState::TeamSelect.allows(GameState::Paused).is_err());
+        assert!(GameState::Paused.allows(GameState::GameActive).is_err());
+        // Team select is the way out of everything but a pause
+        assert!(GameState::Calibrating.allows(GameState::TeamSelect).is_ok());
+        assert!(GameState::GameActive.allows(GameState::TeamSelect).is_ok());
+        assert!(GameState::Paused.allows(GameState::TeamSelect).is_err());
+        assert!(GameState::Identify.allows(GameState::Binding).is_ok());
+    }
+}
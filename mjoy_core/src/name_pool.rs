@@ -0,0 +1,284 @@
+use crate::joypaths::MinimalPathLookup;
+use crate::persist::{PersistError, Store};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NamePoolError {
+    #[error("Failed to read names from {path}: {source}")]
+    Seed {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to import names: {0}")]
+    Import(#[from] serde_json::Error),
+    #[error(transparent)]
+    Persist(#[from] PersistError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PoolName {
+    pub name: String,
+    /// Identity of the pad that last held this name, so it can have it back
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Retired names are kept for their owner's sake but never handed out
+    #[serde(default)]
+    pub retired: bool,
+}
+
+/// A pool entry as exported, with whether a pad currently holds it.
+#[derive(Debug, Serialize)]
+pub struct NameStatus<'a> {
+    #[serde(flatten)]
+    pub entry: &'a PoolName,
+    pub in_use: bool,
+}
+
+/// Every name a pad can be given, in the order they are offered. The pool is seeded
+/// from `binding_names_file` and picks up names added to it later.
+pub struct NamePool {
+    store: Store,
+    names: Vec<PoolName>,
+}
+
+/// Names held by pads, other than the pads in `releasing`.
+fn names_in_use<'a>(mpl: &'a MinimalPathLookup, releasing: &[String]) -> HashSet<&'a str> {
+    mpl.0
+        .iter()
+        .filter(|(identity, _)| !releasing.contains(identity))
+        .filter_map(|(_, np)| np.common_name.as_deref())
+        .collect()
+}
+
+impl NamePool {
+    pub fn new(store: Store) -> Self {
+        NamePool {
+            store,
+            names: Vec::new(),
+        }
+    }
+
+    /// Reads the saved pool and adds any new names from `seed_file`.
+    pub fn load(&mut self, seed_file: &str) -> Result<(), NamePoolError> {
+        self.names = self.store.load()?.unwrap_or_default();
+
+        let seed = std::fs::read_to_string(seed_file).map_err(|source| NamePoolError::Seed {
+            path: seed_file.to_string(),
+            source,
+        })?;
+        for name in seed.lines().map(str::trim).filter(|n| !n.is_empty()) {
+            self.add(name);
+        }
+        self.store.save(&self.names)?;
+        Ok(())
+    }
+
+    /// Adds `name` at the end of the pool unless it is already there.
+    pub fn add(&mut self, name: &str) {
+        if !self.names.iter().any(|entry| entry.name == name) {
+            self.names.push(PoolName {
+                name: name.to_string(),
+                owner: None,
+                retired: false,
+            });
+        }
+    }
+
+    /// Names that can be handed out right now, in pool order. Names held by the pads in
+    /// `releasing` are about to be given up, so they count as free.
+    pub fn free_names(&self, mpl: &MinimalPathLookup, releasing: &[String]) -> Vec<String> {
+        let in_use = names_in_use(mpl, releasing);
+        self.names
+            .iter()
+            .filter(|entry| !entry.retired && !in_use.contains(entry.name.as_str()))
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// The name `identity` held last time, if nobody else has it now.
+    pub fn returning_name(&self, identity: &str, mpl: &MinimalPathLookup) -> Option<String> {
+        let in_use = names_in_use(mpl, &[]);
+        self.names
+            .iter()
+            .find(|entry| {
+                entry.owner.as_deref() == Some(identity)
+                    && !entry.retired
+                    && !in_use.contains(entry.name.as_str())
+            })
+            .map(|entry| entry.name.clone())
+    }
+
+    /// Remembers which pad holds each bound name and saves the pool.
+    pub fn record(&mut self, mpl: &MinimalPathLookup) -> Result<(), PersistError> {
+        for (identity, np) in mpl.0.iter() {
+            let Some(name) = np.common_name.as_ref() else {
+                continue;
+            };
+            self.add(name);
+            for entry in self.names.iter_mut() {
+                if &entry.name == name {
+                    entry.owner = Some(identity.clone());
+                } else if entry.owner.as_ref() == Some(identity) {
+                    // A pad only keeps a claim on its latest name
+                    entry.owner = None;
+                }
+            }
+        }
+        self.store.save(&self.names)?;
+        Ok(())
+    }
+
+    /// Retires or restores `name`. Returns false if the pool has no such name.
+    pub fn set_retired(&mut self, name: &str, retired: bool) -> Result<bool, PersistError> {
+        let Some(entry) = self.names.iter_mut().find(|entry| entry.name == name) else {
+            return Ok(false);
+        };
+        entry.retired = retired;
+        self.store.save(&self.names)?;
+        Ok(true)
+    }
+
+    pub fn status(&self, mpl: &MinimalPathLookup) -> Vec<NameStatus<'_>> {
+        let in_use = names_in_use(mpl, &[]);
+        self.names
+            .iter()
+            .map(|entry| NameStatus {
+                entry,
+                in_use: in_use.contains(entry.name.as_str()),
+            })
+            .collect()
+    }
+
+    pub fn export(&self, mpl: &MinimalPathLookup) -> serde_json::Value {
+        serde_json::to_value(self.status(mpl)).unwrap_or_default()
+    }
+
+    /// Merges an exported pool: known names take the imported owner and retired flag,
+    /// new names are appended. Returns how many entries were read. Nothing is merged if
+    /// any name is blank or listed twice.
+    pub fn import(&mut self, json: &str) -> Result<usize, NamePoolError> {
+        use serde::de::Error;
+        let imported: Vec<PoolName> = serde_json::from_str(json)?;
+        for (i, entry) in imported.iter().enumerate() {
+            if entry.name.trim().is_empty() {
+                return Err(serde_json::Error::custom(format!("entry {} has no name", i)).into());
+            }
+            if imported[..i]
+                .iter()
+                .any(|earlier| earlier.name == entry.name)
+            {
+                return Err(
+                    serde_json::Error::custom(format!("{} is listed twice", entry.name)).into(),
+                );
+            }
+        }
+        for entry in imported.iter() {
+            match self.names.iter_mut().find(|known| known.name == entry.name) {
+                Some(known) => *known = entry.clone(),
+                None => self.names.push(entry.clone()),
+            }
+        }
+        self.store.save(&self.names)?;
+        Ok(imported.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mpl, TempDir};
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("name_pool_{}", name));
+        std::fs::write(dir.join("names.txt"), "Star\nBill\nCoin\n\nEgg\n").unwrap();
+        dir
+    }
+
+    fn open(dir: &TempDir) -> NamePool {
+        let mut pool = NamePool::new(Store::new(dir.join("names.json"), 0));
+        pool.load(dir.join("names.txt").to_str().unwrap()).unwrap();
+        pool
+    }
+
+    #[test]
+    fn regulars_get_their_name_back() {
+        let dir = temp_dir("regulars");
+        let mut pool = open(&dir);
+        assert_eq!(
+            pool.free_names(&mpl(&[]), &[]),
+            vec!["Star", "Bill", "Coin", "Egg"]
+        );
+
+        pool.record(&mpl(&[("1:1.0", Some("Coin")), ("2:1.0", Some("Star"))]))
+            .unwrap();
+        let bound = mpl(&[("2:1.0", Some("Star"))]);
+        assert_eq!(pool.free_names(&bound, &[]), vec!["Bill", "Coin", "Egg"]);
+        assert_eq!(
+            pool.free_names(&bound, &["2:1.0".to_string()]),
+            vec!["Star", "Bill", "Coin", "Egg"]
+        );
+
+        // Pad 1 comes back next session and is offered Coin again, from disk
+        let pool = open(&dir);
+        assert_eq!(
+            pool.returning_name("1:1.0", &mpl(&[])),
+            Some("Coin".to_string())
+        );
+        // Unless someone else took it meanwhile
+        assert_eq!(
+            pool.returning_name("1:1.0", &mpl(&[("3:1.0", Some("Coin"))])),
+            None
+        );
+    }
+
+    #[test]
+    fn retired_names_are_not_offered() {
+        let dir = temp_dir("retired");
+        let mut pool = open(&dir);
+        pool.record(&mpl(&[("1:1.0", Some("Bill"))])).unwrap();
+        assert!(pool.set_retired("Bill", true).unwrap());
+        assert!(!pool.set_retired("Nobody", true).unwrap());
+        assert_eq!(pool.free_names(&mpl(&[]), &[]), vec!["Star", "Coin", "Egg"]);
+        assert_eq!(pool.returning_name("1:1.0", &mpl(&[])), None);
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let dir = temp_dir("export");
+        let mut pool = open(&dir);
+        pool.record(&mpl(&[("1:1.0", Some("Egg"))])).unwrap();
+        pool.set_retired("Bill", true).unwrap();
+        let exported = pool.export(&mpl(&[("1:1.0", Some("Egg"))]));
+        assert_eq!(exported[3]["name"], "Egg");
+        assert_eq!(exported[3]["in_use"], true);
+
+        let other = temp_dir("import");
+        let mut imported = open(&other);
+        assert_eq!(imported.import(&exported.to_string()).unwrap(), 4);
+        imported
+            .import(r#"[{"name": "Kart", "owner": "5:1.0"}]"#)
+            .unwrap();
+        assert_eq!(
+            imported.free_names(&mpl(&[]), &[]),
+            vec!["Star", "Coin", "Egg", "Kart"]
+        );
+        assert_eq!(
+            imported.returning_name("1:1.0", &mpl(&[])),
+            Some("Egg".to_string())
+        );
+        assert!(imported.import("not json").is_err());
+        for bad in [
+            r#"[{"name": "Lamp"}, {"name": " "}]"#,
+            r#"[{"name": "Lamp"}, {"name": "Lamp", "retired": true}]"#,
+        ] {
+            assert!(matches!(
+                imported.import(bad),
+                Err(NamePoolError::Import(_))
+            ));
+        }
+        assert!(!imported
+            .free_names(&mpl(&[]), &[])
+            .contains(&"Lamp".to_string()));
+    }
+}
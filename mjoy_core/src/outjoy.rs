@@ -1,8 +1,11 @@
 use crate::joypaths;
 use crate::Team;
 use crate::TeamLock;
+use command_server::metrics::Metrics;
+use command_server::AggregationMode;
 use gilrs;
 use software_joystick::*;
+use std::cell::Cell;
 use strum::IntoEnumIterator;
 
 pub struct Outjoys {
@@ -12,6 +15,8 @@ pub struct Outjoys {
 pub struct Outjoy {
     team: Team,
     joy: Joystick,
+    /// What was held after the last update, one bit per stick direction and button
+    held: Cell<u32>,
 }
 
 fn inbutton_to_outbutton(b: &crate::injoy::NamedButton) -> software_joystick::Button {
@@ -36,10 +41,42 @@ fn inaxis_to_outaxis(a: &crate::injoy::NamedAxis) -> software_joystick::Axis {
     }
 }
 
+/// A team's stick deflection from the sum of its `count` players' -1, 0 or 1 readings.
+fn team_axis(mode: AggregationMode, sum: f32, count: usize) -> f32 {
+    if count == 0 || sum == 0.0 {
+        return 0.0;
+    }
+    match mode {
+        AggregationMode::Threshold => {
+            let average = (sum / count as f32).clamp(-1.0f32, 1.0f32);
+            average.signum() * average.abs().powf(2.0f32)
+        }
+        AggregationMode::Any => sum.signum(),
+        AggregationMode::All if sum.abs() >= count as f32 => sum.signum(),
+        AggregationMode::All => 0.0,
+    }
+}
+
+/// Whether a team presses a button `sum` of its `count` players are holding.
+fn team_presses(mode: AggregationMode, sum: f32, count: usize, threshold: f32) -> bool {
+    if count == 0 {
+        return false;
+    }
+    match mode {
+        AggregationMode::Threshold => sum / count as f32 > threshold,
+        AggregationMode::Any => sum > 0.0,
+        AggregationMode::All => sum >= count as f32,
+    }
+}
+
 impl Outjoy {
     pub fn new(team: Team, index: u32) -> Self {
         let joy = Joystick::new(format!("Buster{}", index)).unwrap();
-        Self { team, joy }
+        Self {
+            team,
+            joy,
+            held: Cell::new(0),
+        }
     }
 
     fn mutate_team(&mut self, team: Team) {
@@ -76,7 +113,8 @@ impl Outjoy {
         }
     }
 
-    fn update_axes<'b, 'c, 'd, 'e>(&self, context: &'d mut UpdateContext<'b, 'c, 'e>) {
+    /// Returns the stick directions pushed, two bits per axis.
+    fn update_axes<'b, 'c, 'd, 'e>(&self, context: &'d mut UpdateContext<'b, 'c, 'e>) -> u32 {
         use crate::injoy::NamedAxis;
 
         let mut fb_team = None;
@@ -89,6 +127,7 @@ impl Outjoy {
 
         let lefties = ["<".to_string(), ">".to_string()];
         let upies = ["^".to_string(), "v".to_string()];
+        let mut held = 0;
 
         for (i, inaxis) in crate::injoy::NamedAxis::iter().enumerate() {
             let mut sum = 0 as f32;
@@ -180,15 +219,14 @@ impl Outjoy {
                 }
             }
 
-            let average = match count {
-                0 => 0 as f32,
-                _ => sum / count as f32,
-            };
-            let average = average.clamp(-1.0f32, 1.0f32);
-            let pow = average.abs().powf(2.0f32);
-            let average = average.signum() * pow;
+            let average = team_axis(context.aggregation, sum, count);
             let average_i = (average * 512f32) as i32;
             self.joy.move_axis(out_axis, average_i).unwrap();
+            if average < 0.0 {
+                held |= 1 << (2 * i);
+            } else if average > 0.0 {
+                held |= 1 << (2 * i + 1);
+            }
 
             let letter = Self::inaxis_to_letter(&inaxis, average);
             let fb_team = match fb_team.as_mut() {
@@ -212,9 +250,11 @@ impl Outjoy {
                 }
             }
         }
+        held
     }
 
-    fn update_buttons<'b, 'c, 'd, 'e>(&self, context: &'d mut UpdateContext<'b, 'c, 'e>) {
+    /// Returns the buttons pressed, one bit each.
+    fn update_buttons<'b, 'c, 'd, 'e>(&self, context: &'d mut UpdateContext<'b, 'c, 'e>) -> u32 {
         use crate::injoy::NamedButton;
 
         let mut fb_team = None;
@@ -224,6 +264,7 @@ impl Outjoy {
                 break;
             }
         }
+        let mut held = 0;
 
         for (i, inbutton) in crate::injoy::NamedButton::iter().enumerate() {
             let mut sum = 0 as f32;
@@ -303,14 +344,12 @@ impl Outjoy {
                 }
             }
 
-            let average = match count {
-                0 => 0 as f32,
-                _ => sum / count as f32,
-            };
+            let pressed = team_presses(context.aggregation, sum, count, context.button_threshold);
 
-            self.joy
-                .button_press(outbutton, average > context.button_threshold)
-                .unwrap();
+            self.joy.button_press(outbutton, pressed).unwrap();
+            if pressed {
+                held |= 1 << i;
+            }
 
             let fb_team = match fb_team.as_mut() {
                 Some(fb_team) => fb_team,
@@ -320,7 +359,7 @@ impl Outjoy {
             let letter = Self::inbutton_to_letter(&inbutton);
             for f in fb_team.feedback.0.iter_mut() {
                 if f.button == letter {
-                    let punp = if average > context.button_threshold {
+                    let punp = if pressed {
                         mjoy_gui::gui::feedback_info::PressState::Pressed
                     } else {
                         mjoy_gui::gui::feedback_info::PressState::Unpressed
@@ -329,12 +368,41 @@ impl Outjoy {
                 }
             }
         }
+        held
+    }
+
+    /// Centres the stick and lets go of every button.
+    fn release(&self) {
+        for inaxis in crate::injoy::NamedAxis::iter() {
+            self.joy.move_axis(inaxis_to_outaxis(&inaxis), 0).unwrap();
+        }
+        for inbutton in crate::injoy::NamedButton::iter() {
+            self.joy
+                .button_press(inbutton_to_outbutton(&inbutton), false)
+                .unwrap();
+        }
+        self.joy.synchronise().unwrap();
+        self.held.set(0);
     }
 
     pub fn update<'b, 'c, 'd, 'e>(&self, context: &'d mut UpdateContext<'b, 'c, 'e>) {
-        self.update_axes(context);
-        self.update_buttons(context);
+        let axes = self.update_axes(context);
+        let buttons = self.update_buttons(context);
         self.joy.synchronise().unwrap();
+
+        let held = axes | buttons << (2 * crate::injoy::NamedAxis::iter().count());
+        let pressed = held & !self.held.replace(held);
+        let team = [("team", self.team.name.as_str())];
+        if pressed != 0 {
+            context.metrics.add(
+                "mjoy_team_presses_total",
+                &team,
+                f64::from(pressed.count_ones()),
+            );
+        }
+        context
+            .metrics
+            .set("mjoy_team_inputs_held", &team, f64::from(held.count_ones()));
     }
 }
 
@@ -345,6 +413,19 @@ pub struct UpdateContext<'b, 'c, 'e> {
     pub feedback: &'e mut mjoy_gui::gui::feedback_info::FeedbackInfo,
     pub hat_only_player_names: &'b Vec<String>,
     pub button_threshold: f32,
+    pub aggregation: AggregationMode,
+    pub metrics: &'b Metrics,
+}
+
+/// A virtual pad going away, e.g. when a preset brings fewer teams or on shutdown, must
+/// not leave anything held.
+impl Drop for Outjoy {
+    fn drop(&mut self) {
+        // Releasing unwraps, which would abort if we are already unwinding
+        if !std::thread::panicking() {
+            self.release();
+        }
+    }
 }
 
 impl Outjoys {
@@ -356,9 +437,22 @@ impl Outjoys {
         Self { outjoys }
     }
 
+    /// A preset can bring a different number of teams, so joysticks are added or
+    /// dropped to match.
     pub fn overwrite(&mut self, tl: &TeamLock) {
+        self.outjoys.truncate(tl.teams.len());
         for (i, team) in tl.teams.iter().enumerate() {
-            self.outjoys.get_mut(i).unwrap().mutate_team(team.clone());
+            match self.outjoys.get_mut(i) {
+                Some(outjoy) => outjoy.mutate_team(team.clone()),
+                None => self.outjoys.push(Outjoy::new(team.clone(), team.out_index)),
+            }
+        }
+    }
+
+    /// Nothing stays held while the game is paused.
+    pub fn release(&self) {
+        for outjoy in self.outjoys.iter() {
+            outjoy.release();
         }
     }
 
@@ -368,3 +462,31 @@ impl Outjoys {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_mode_keeps_the_squared_average() {
+        assert_eq!(team_axis(AggregationMode::Threshold, 0.0, 0), 0.0);
+        assert_eq!(team_axis(AggregationMode::Threshold, 2.0, 4), 0.25);
+        assert_eq!(team_axis(AggregationMode::Threshold, -4.0, 4), -1.0);
+        assert!(team_presses(AggregationMode::Threshold, 3.0, 4, 0.7));
+        assert!(!team_presses(AggregationMode::Threshold, 2.0, 4, 0.7));
+    }
+
+    #[test]
+    fn any_and_all_modes() {
+        assert_eq!(team_axis(AggregationMode::Any, 1.0, 4), 1.0);
+        assert_eq!(team_axis(AggregationMode::Any, 0.0, 4), 0.0);
+        assert_eq!(team_axis(AggregationMode::All, -3.0, 4), 0.0);
+        assert_eq!(team_axis(AggregationMode::All, -4.0, 4), -1.0);
+
+        assert!(team_presses(AggregationMode::Any, 1.0, 4, 0.9));
+        assert!(!team_presses(AggregationMode::All, 3.0, 4, 0.1));
+        assert!(team_presses(AggregationMode::All, 4.0, 4, 0.9));
+        // Nobody connected never presses
+        assert!(!team_presses(AggregationMode::All, 0.0, 0, 0.9));
+    }
+}
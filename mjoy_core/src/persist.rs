@@ -0,0 +1,311 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Bump when the on-disk layout of a stored type changes, and teach `load` to migrate.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistError {
+    #[error("Failed to access {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse {path}: {source}")]
+    Format {
+        path: String,
+        source: serde_json::Error,
+    },
+    #[error("{path} has schema version {version}, newer than this build understands")]
+    UnsupportedVersion { path: String, version: u32 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    data: T,
+}
+
+/// A JSON file that is written atomically, with a schema version and rotating backups.
+/// Files from before versioning (the bare data) are still read and get upgraded on
+/// the next save.
+pub struct Store {
+    path: PathBuf,
+    backups: usize,
+    last_written: Option<String>,
+    /// Set when `load` found a file from a newer build, which `save` must not replace
+    newer_version: Option<u32>,
+}
+
+fn io_error(path: &Path, source: std::io::Error) -> PersistError {
+    PersistError::Io {
+        path: path.to_string_lossy().to_string(),
+        source,
+    }
+}
+
+impl Store {
+    pub fn new<P: AsRef<Path>>(path: P, backups: usize) -> Self {
+        Store {
+            path: path.as_ref().to_path_buf(),
+            backups,
+            last_written: None,
+            newer_version: None,
+        }
+    }
+
+    fn backup_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".bak.{}", n));
+        PathBuf::from(name)
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".tmp");
+        PathBuf::from(name)
+    }
+
+    /// `Ok(None)` when neither the file nor any backup exists. A file that fails to parse
+    /// falls back to the newest readable backup, but one written by a newer build is an
+    /// error: an older backup would lose its data on the next save.
+    pub fn load<T: DeserializeOwned + Serialize>(&mut self) -> Result<Option<T>, PersistError> {
+        let mut first_error = None;
+        let candidates: Vec<PathBuf> = std::iter::once(self.path.clone())
+            .chain((1..=self.backups).map(|n| self.backup_path(n)))
+            .collect();
+
+        for (i, path) in candidates.iter().enumerate() {
+            let text = match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    first_error.get_or_insert(io_error(path, e));
+                    continue;
+                }
+            };
+            match Self::parse::<T>(path, &text) {
+                Ok((data, current)) => {
+                    if i > 0 {
+                        tracing::warn!("Recovered {} from {}", self.path.display(), path.display());
+                    }
+                    // Only a current file read straight from disk counts as written
+                    self.last_written = if i == 0 && current {
+                        Some(Self::render(&data).map_err(|source| PersistError::Format {
+                            path: path.to_string_lossy().to_string(),
+                            source,
+                        })?)
+                    } else {
+                        None
+                    };
+                    return Ok(Some(data));
+                }
+                Err(PersistError::UnsupportedVersion { path, version }) => {
+                    self.newer_version = Some(version);
+                    return Err(PersistError::UnsupportedVersion { path, version });
+                }
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the data and whether it was already at `SCHEMA_VERSION`.
+    fn parse<T: DeserializeOwned>(path: &Path, text: &str) -> Result<(T, bool), PersistError> {
+        let format_error = |source| PersistError::Format {
+            path: path.to_string_lossy().to_string(),
+            source,
+        };
+        let value: serde_json::Value = serde_json::from_str(text).map_err(format_error)?;
+
+        let versioned = value.get("version").is_some() && value.get("data").is_some();
+        if !versioned {
+            // Unversioned files predate the envelope, the whole document is the data
+            let data = serde_json::from_value(value).map_err(format_error)?;
+            return Ok((data, false));
+        }
+
+        let versioned: Versioned<serde_json::Value> =
+            serde_json::from_value(value).map_err(format_error)?;
+        if versioned.version > SCHEMA_VERSION {
+            return Err(PersistError::UnsupportedVersion {
+                path: path.to_string_lossy().to_string(),
+                version: versioned.version,
+            });
+        }
+        let data = serde_json::from_value(versioned.data).map_err(format_error)?;
+        Ok((data, versioned.version == SCHEMA_VERSION))
+    }
+
+    fn render<T: Serialize>(data: &T) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(&Versioned {
+            version: SCHEMA_VERSION,
+            data,
+        })
+    }
+
+    /// Writes `data` unless it matches what was last loaded or saved. Returns whether
+    /// the file was touched.
+    pub fn save<T: Serialize>(&mut self, data: &T) -> Result<bool, PersistError> {
+        if let Some(version) = self.newer_version {
+            return Err(PersistError::UnsupportedVersion {
+                path: self.path.to_string_lossy().to_string(),
+                version,
+            });
+        }
+        let text = Self::render(data).map_err(|source| PersistError::Format {
+            path: self.path.to_string_lossy().to_string(),
+            source,
+        })?;
+        if self.last_written.as_ref() == Some(&text) {
+            return Ok(false);
+        }
+
+        let temp = self.temp_path();
+        {
+            let mut file = fs::File::create(&temp).map_err(|e| io_error(&temp, e))?;
+            file.write_all(text.as_bytes())
+                .map_err(|e| io_error(&temp, e))?;
+            file.sync_all().map_err(|e| io_error(&temp, e))?;
+        }
+
+        if self.backups > 0 && self.path.exists() {
+            for n in (1..self.backups).rev() {
+                let from = self.backup_path(n);
+                if from.exists() {
+                    let to = self.backup_path(n + 1);
+                    fs::rename(&from, &to).map_err(|e| io_error(&from, e))?;
+                }
+            }
+            let first = self.backup_path(1);
+            fs::copy(&self.path, &first).map_err(|e| io_error(&first, e))?;
+        }
+
+        fs::rename(&temp, &self.path).map_err(|e| io_error(&self.path, e))?;
+        self.sync_dir()?;
+        self.last_written = Some(text);
+        Ok(true)
+    }
+
+    /// Makes the rename durable too, not just the new file's contents.
+    fn sync_dir(&self) -> Result<(), PersistError> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| io_error(dir, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Roster {
+        teams: Vec<String>,
+    }
+
+    fn roster(teams: &[&str]) -> Roster {
+        Roster {
+            teams: teams.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn missing_file_loads_nothing() {
+        let dir = TempDir::new("persist_missing");
+        let mut store = Store::new(dir.join("teamlock.json"), 3);
+        assert_eq!(store.load::<Roster>().unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip_only_writes_changes() {
+        let dir = TempDir::new("persist_round_trip");
+        let path = dir.join("teamlock.json");
+        let mut store = Store::new(&path, 3);
+
+        assert!(store.save(&roster(&["Star"])).unwrap());
+        assert!(!store.save(&roster(&["Star"])).unwrap());
+        assert!(store.save(&roster(&["Star", "Coin"])).unwrap());
+
+        let mut reopened = Store::new(&path, 3);
+        assert_eq!(
+            reopened.load::<Roster>().unwrap(),
+            Some(roster(&["Star", "Coin"]))
+        );
+        assert!(!reopened.save(&roster(&["Star", "Coin"])).unwrap());
+
+        let on_disk: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk["version"], SCHEMA_VERSION);
+        assert!(!store.temp_path().exists());
+    }
+
+    #[test]
+    fn migrates_unversioned_file() {
+        let dir = TempDir::new("persist_migrate");
+        let path = dir.join("teamlock.json");
+        fs::write(&path, r#"{"teams": ["Star"]}"#).unwrap();
+
+        let mut store = Store::new(&path, 3);
+        assert_eq!(store.load::<Roster>().unwrap(), Some(roster(&["Star"])));
+        // Same data, but the file still needs upgrading
+        assert!(store.save(&roster(&["Star"])).unwrap());
+        let on_disk: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(on_disk["data"]["teams"][0], "Star");
+    }
+
+    #[test]
+    fn rotates_backups_and_recovers_from_corruption() {
+        let dir = TempDir::new("persist_backups");
+        let path = dir.join("bindings.json");
+        let mut store = Store::new(&path, 2);
+        for name in ["Star", "Coin", "Egg", "Kart"] {
+            store.save(&roster(&[name])).unwrap();
+        }
+        assert!(store.backup_path(1).exists());
+        assert!(store.backup_path(2).exists());
+        assert!(!store.backup_path(3).exists());
+
+        fs::write(&path, "{ not json").unwrap();
+        let mut reopened = Store::new(&path, 2);
+        assert_eq!(reopened.load::<Roster>().unwrap(), Some(roster(&["Egg"])));
+    }
+
+    #[test]
+    fn rejects_newer_schema() {
+        let dir = TempDir::new("persist_newer");
+        let path = dir.join("teamlock.json");
+        let mut store = Store::new(&path, 2);
+        store.save(&roster(&["Star"])).unwrap();
+        store.save(&roster(&["Coin"])).unwrap();
+        fs::write(&path, r#"{"version": 99, "data": {"teams": []}}"#).unwrap();
+
+        // Not recovered from the backup, and not overwritten either
+        let mut reopened = Store::new(&path, 2);
+        assert!(matches!(
+            reopened.load::<Roster>(),
+            Err(PersistError::UnsupportedVersion { version: 99, .. })
+        ));
+        assert!(matches!(
+            reopened.save(&roster(&["Egg"])),
+            Err(PersistError::UnsupportedVersion { version: 99, .. })
+        ));
+        assert!(fs::read_to_string(&path).unwrap().contains("99"));
+    }
+}
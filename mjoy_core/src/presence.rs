@@ -0,0 +1,144 @@
+use mjoy_gui::gui::feedback_info::FeedbackInfo;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Tracks team players whose pads have gone away, so a quick replug keeps their team slot.
+pub struct Presence {
+    disconnected: HashMap<String, Instant>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Presence {
+            disconnected: HashMap::new(),
+        }
+    }
+
+    pub fn is_connected(&self, player: &str) -> bool {
+        !self.disconnected.contains_key(player)
+    }
+
+    /// Marks every team player missing from `connected` as disconnected, keeping the
+    /// original disconnect time for players that were already gone.
+    pub fn update(&mut self, teams: &crate::TeamLock, connected: &HashSet<String>, now: Instant) {
+        let mut on_teams = HashSet::new();
+        for team in teams.teams.iter() {
+            for player in team.players.iter() {
+                on_teams.insert(player);
+                if connected.contains(player) {
+                    if self.disconnected.remove(player).is_some() {
+                        tracing::info!("{} reconnected", player);
+                    }
+                } else if !self.disconnected.contains_key(player) {
+                    tracing::warn!("{} disconnected", player);
+                    self.disconnected.insert(player.clone(), now);
+                }
+            }
+        }
+        self.disconnected
+            .retain(|player, _| on_teams.contains(player));
+    }
+
+    /// Returns and forgets the players that have been gone for longer than `grace`.
+    pub fn expire(&mut self, now: Instant, grace: Duration) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.disconnected.retain(|player, since| {
+            if now.duration_since(*since) > grace {
+                expired.push(player.clone());
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+
+    pub fn mark_feedback(&self, feedback: &mut FeedbackInfo) {
+        for team in feedback.teams.iter_mut() {
+            for player in team.players.iter_mut() {
+                player.connected = self.is_connected(&player.player_name);
+            }
+        }
+    }
+}
+
+/// Names of the bound players whose pads gilrs currently sees.
+pub fn connected_players(
+    gilrs: &gilrs::Gilrs,
+    epl: &crate::joypaths::EventPathLookup,
+    mpl: &crate::joypaths::MinimalPathLookup,
+) -> HashSet<String> {
+    let mut connected = HashSet::new();
+    for (_id, gamepad) in gilrs.gamepads() {
+        let Some(identity) = epl.0.get(gamepad.devpath()) else {
+            continue;
+        };
+        let Some(named_path) = mpl.0.get(identity) else {
+            continue;
+        };
+        if let Some(common_name) = named_path.common_name.as_ref() {
+            connected.insert(common_name.clone());
+        }
+    }
+    connected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn teams(players: &[&str]) -> crate::TeamLock {
+        crate::TeamLock {
+            teams: vec![crate::Team {
+                name: "Lucky Bulldogs".to_string(),
+                players: players.iter().map(|p| p.to_string()).collect(),
+                out_index: 0,
+            }],
+        }
+    }
+
+    fn names(players: &[&str]) -> HashSet<String> {
+        players.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn replug_within_grace_keeps_player() {
+        let grace = Duration::from_secs(30);
+        let start = Instant::now();
+        let tl = teams(&["Star", "Coin"]);
+        let mut presence = Presence::new();
+
+        presence.update(&tl, &names(&["Coin"]), start);
+        assert!(!presence.is_connected("Star"));
+        assert!(presence.is_connected("Coin"));
+        assert!(presence
+            .expire(start + Duration::from_secs(10), grace)
+            .is_empty());
+
+        presence.update(
+            &tl,
+            &names(&["Star", "Coin"]),
+            start + Duration::from_secs(20),
+        );
+        assert!(presence.is_connected("Star"));
+        assert!(presence
+            .expire(start + Duration::from_secs(60), grace)
+            .is_empty());
+    }
+
+    #[test]
+    fn expires_after_grace() {
+        let grace = Duration::from_secs(30);
+        let start = Instant::now();
+        let tl = teams(&["Star", "Coin"]);
+        let mut presence = Presence::new();
+
+        presence.update(&tl, &names(&["Coin"]), start);
+        // A second hotplug must not restart Star's clock
+        presence.update(&tl, &names(&["Coin"]), start + Duration::from_secs(20));
+        assert_eq!(
+            presence.expire(start + Duration::from_secs(31), grace),
+            vec!["Star".to_string()]
+        );
+    }
+}
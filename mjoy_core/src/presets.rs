@@ -0,0 +1,73 @@
+use crate::persist::{PersistError, Store};
+use crate::TeamLock;
+use std::collections::BTreeMap;
+
+/// Named team setups ("office-league", "family-night") that recurring groups can restore
+/// in one go. Each preset is a whole `TeamLock`: names, rosters and out indices.
+pub struct Presets {
+    store: Store,
+    presets: BTreeMap<String, TeamLock>,
+}
+
+impl Presets {
+    /// Starts with no presets until `load` succeeds.
+    pub fn new(store: Store) -> Self {
+        Presets {
+            store,
+            presets: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), PersistError> {
+        self.presets = self.store.load()?.unwrap_or_default();
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.presets.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TeamLock> {
+        self.presets.get(name)
+    }
+
+    /// Overwrites any preset with the same name.
+    pub fn save(&mut self, name: &str, teams: &TeamLock) -> Result<(), PersistError> {
+        self.presets.insert(name.to_string(), teams.clone());
+        self.store.save(&self.presets)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn save_list_and_reload() {
+        let dir = TempDir::new("presets");
+        let path = dir.join("presets.json");
+
+        let teams = TeamLock {
+            teams: vec![crate::Team {
+                name: "Lucky Bulldogs".to_string(),
+                players: vec!["Star".to_string()],
+                out_index: 2,
+            }],
+        };
+
+        let mut presets = Presets::new(Store::new(&path, 0));
+        presets.load().unwrap();
+        presets.save("office-league", &teams).unwrap();
+        presets.save("family-night", &teams).unwrap();
+
+        let mut presets = Presets::new(Store::new(&path, 0));
+        presets.load().unwrap();
+        assert_eq!(presets.names(), vec!["family-night", "office-league"]);
+        let loaded = presets.get("office-league").unwrap();
+        assert_eq!(loaded.teams[0].players, vec!["Star"]);
+        assert_eq!(loaded.teams[0].out_index, 2);
+        assert!(presets.get("nope").is_none());
+    }
+}
@@ -0,0 +1,418 @@
+use command_server::{AggregationMode, Command, ThresholdError, ThresholdProfile};
+use mjoy_gui::gui::feedback_info::CueDisplay;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    #[error("failed to read timeline {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse timeline {path}: {source}")]
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    #[error("cue {index} in timeline {path}: {reason}")]
+    Cue {
+        path: String,
+        /// Counted from 0, in file order
+        index: usize,
+        reason: CueError,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CueError {
+    #[error("{0} seconds is not a time from the start")]
+    At(f32),
+    #[error("an announcement can't stay up for {0} seconds")]
+    For(f32),
+    #[error(transparent)]
+    Threshold(#[from] ThresholdError),
+}
+
+/// What a cue does when its time comes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "do", rename_all = "snake_case")]
+pub enum Action {
+    Setup,
+    /// Resized to `teams` if given, like `/teams`
+    TeamSelect {
+        #[serde(default)]
+        teams: Option<usize>,
+    },
+    Start,
+    Pause,
+    Resume,
+    Threshold {
+        profile: ThresholdProfile,
+    },
+    Aggregation {
+        mode: AggregationMode,
+    },
+    /// Only shown on screen
+    Announce {
+        text: String,
+        #[serde(default = "default_announce_secs")]
+        for_secs: f32,
+    },
+}
+
+fn default_announce_secs() -> f32 {
+    10.0
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Setup => "setup",
+            Action::TeamSelect { .. } => "team select",
+            Action::Start => "start",
+            Action::Pause => "pause",
+            Action::Resume => "resume",
+            Action::Threshold { .. } => "threshold",
+            Action::Aggregation { .. } => "aggregation",
+            Action::Announce { .. } => "announcement",
+        }
+    }
+
+    /// The command main should act on, the same as if it came from the server.
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            Action::Setup => Some(Command::Setup),
+            Action::TeamSelect { teams } => Some(Command::Teams(*teams)),
+            Action::Start => Some(Command::Start),
+            Action::Pause => Some(Command::Pause),
+            Action::Resume => Some(Command::Resume),
+            Action::Threshold { profile } => Some(Command::SetThreshold(*profile)),
+            Action::Aggregation { mode } => Some(Command::SetAggregationMode(*mode)),
+            Action::Announce { .. } => None,
+        }
+    }
+}
+
+/// One line of the timeline file, e.g.
+/// `{"at_secs": 600, "label": "Round 1", "action": {"do": "start"}}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cue {
+    /// Seconds from the start of the timeline
+    pub at_secs: f32,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub action: Action,
+}
+
+fn seconds(secs: f32) -> Option<Duration> {
+    Duration::try_from_secs_f32(secs).ok()
+}
+
+impl Cue {
+    /// Only cues from `Scheduler::load` are checked, anything else comes at the end.
+    fn at(&self) -> Duration {
+        seconds(self.at_secs).unwrap_or(Duration::MAX)
+    }
+
+    fn check(&self) -> Result<(), CueError> {
+        seconds(self.at_secs).ok_or(CueError::At(self.at_secs))?;
+        match &self.action {
+            Action::Announce { for_secs, .. } => {
+                seconds(*for_secs).ok_or(CueError::For(*for_secs))?;
+            }
+            Action::Threshold { profile } => {
+                profile.check()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn label(&self) -> String {
+        match (&self.label, &self.action) {
+            (Some(label), _) => label.clone(),
+            (None, Action::Announce { text, .. }) => text.clone(),
+            (None, action) => action.name().to_string(),
+        }
+    }
+}
+
+/// Runs a timeline of cues. Its clock only moves while running, so pausing the
+/// schedule holds every later cue back by as long as the pause. Pausing the game
+/// does not pause the schedule, or a scripted Resume could never come.
+#[derive(Default)]
+pub struct Scheduler {
+    cues: Vec<Cue>,
+    /// Index of the next cue to fire
+    next: usize,
+    /// Timeline time run before the current stretch
+    elapsed_before: Duration,
+    /// When the current stretch began, None while paused or before starting
+    running_since: Option<Instant>,
+    /// The text and the timeline time it comes down
+    announcement: Option<(String, Duration)>,
+}
+
+fn minutes_seconds(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+impl Scheduler {
+    pub fn new(mut cues: Vec<Cue>) -> Self {
+        // Stable, so cues at the same offset fire in file order
+        cues.sort_by(|a, b| a.at_secs.total_cmp(&b.at_secs));
+        Scheduler {
+            cues,
+            ..Default::default()
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, SchedulerError> {
+        let text = std::fs::read_to_string(path).map_err(|source| SchedulerError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+        let cues: Vec<Cue> =
+            serde_json::from_str(&text).map_err(|source| SchedulerError::Parse {
+                path: path.to_string(),
+                source,
+            })?;
+        for (index, cue) in cues.iter().enumerate() {
+            cue.check().map_err(|reason| SchedulerError::Cue {
+                path: path.to_string(),
+                index,
+                reason,
+            })?;
+        }
+        Ok(Self::new(cues))
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        self.next = 0;
+        self.elapsed_before = Duration::ZERO;
+        self.running_since = Some(now);
+        self.announcement = None;
+    }
+
+    fn started(&self) -> bool {
+        self.running_since.is_some() || self.elapsed_before > Duration::ZERO || self.next > 0
+    }
+
+    /// False when it wasn't running.
+    pub fn pause(&mut self, now: Instant) -> bool {
+        match self.running_since.take() {
+            Some(since) => {
+                self.elapsed_before += now.saturating_duration_since(since);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// False when it wasn't paused.
+    pub fn resume(&mut self, now: Instant) -> bool {
+        if self.running_since.is_some() || !self.started() || self.finished() {
+            return false;
+        }
+        self.running_since = Some(now);
+        true
+    }
+
+    fn finished(&self) -> bool {
+        self.next >= self.cues.len()
+    }
+
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        self.elapsed_before
+            + self
+                .running_since
+                .map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+
+    /// Commands for every cue whose time has come since the last call, in order.
+    pub fn due(&mut self, now: Instant) -> Vec<Command> {
+        if self.running_since.is_none() {
+            return Vec::new();
+        }
+        let elapsed = self.elapsed(now);
+        let mut commands = Vec::new();
+        while let Some(cue) = self.cues.get(self.next) {
+            if cue.at() > elapsed {
+                break;
+            }
+            tracing::info!("Cue at {}: {}", minutes_seconds(cue.at()), cue.label());
+            if let Action::Announce { text, for_secs } = &cue.action {
+                let until = cue
+                    .at()
+                    .saturating_add(seconds(*for_secs).unwrap_or_default());
+                self.announcement = Some((text.clone(), until));
+            }
+            commands.extend(cue.action.command());
+            self.next += 1;
+        }
+        commands
+    }
+
+    fn current(&self) -> Option<&Cue> {
+        self.next.checked_sub(1).and_then(|i| self.cues.get(i))
+    }
+
+    fn upcoming(&self) -> Option<&Cue> {
+        self.cues.get(self.next)
+    }
+
+    pub fn display(&self, now: Instant) -> CueDisplay {
+        if !self.started() {
+            return CueDisplay::default();
+        }
+        let paused = if self.running_since.is_none() && !self.finished() {
+            " (paused)"
+        } else {
+            ""
+        };
+        CueDisplay {
+            current: self
+                .current()
+                .map(|cue| format!("Now: {}{}", cue.label(), paused)),
+            next: self.upcoming().map(|cue| {
+                let left = cue.at().saturating_sub(self.elapsed(now));
+                format!("Next: {} in {}", cue.label(), minutes_seconds(left))
+            }),
+            announcement: self
+                .announcement
+                .as_ref()
+                .filter(|(_, until)| self.elapsed(now) < *until)
+                .map(|(text, _)| text.clone()),
+        }
+    }
+
+    /// For the state endpoints and the event stream. Whole seconds, so the stream
+    /// sends at most one update a second.
+    pub fn to_json(&self, now: Instant) -> Value {
+        if self.cues.is_empty() {
+            return Value::Null;
+        }
+        json!({
+            "running": self.running_since.is_some(),
+            "finished": self.finished(),
+            "elapsed_secs": self.elapsed(now).as_secs(),
+            "current": self.current().map(Cue::label),
+            "next": self.upcoming().map(Cue::label),
+            "next_at_secs": self.upcoming().map(|cue| cue.at_secs),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn timeline() -> Scheduler {
+        let cues: Vec<Cue> = serde_json::from_str(
+            r#"[
+                {"at_secs": 300, "label": "Round 1", "action": {"do": "start"}},
+                {"at_secs": 0, "action": {"do": "team_select"}},
+                {"at_secs": 540, "action": {"do": "announce", "text": "Chaos!"}},
+                {"at_secs": 540, "action": {"do": "threshold", "profile": {"drift": {"min": 0.3, "max": 0.9}}}}
+            ]"#,
+        )
+        .unwrap();
+        Scheduler::new(cues)
+    }
+
+    #[test]
+    fn fires_cues_in_order() {
+        let mut scheduler = timeline();
+        let start = Instant::now();
+        assert!(scheduler.due(start).is_empty());
+
+        scheduler.start(start);
+        assert!(matches!(
+            scheduler.due(start).as_slice(),
+            [Command::Teams(None)]
+        ));
+        assert!(scheduler.due(start + Duration::from_secs(299)).is_empty());
+        assert_eq!(
+            scheduler.display(start + Duration::from_secs(299)).next,
+            Some("Next: Round 1 in 0:01".to_string())
+        );
+
+        // A stalled loop still gets everything it missed, announcements only show
+        let commands = scheduler.due(start + Duration::from_secs(600));
+        assert!(matches!(
+            commands.as_slice(),
+            [
+                Command::Start,
+                Command::SetThreshold(ThresholdProfile::Drift { .. })
+            ]
+        ));
+        let display = scheduler.display(start + Duration::from_secs(545));
+        assert_eq!(display.current, Some("Now: threshold".to_string()));
+        assert_eq!(display.announcement, Some("Chaos!".to_string()));
+        assert_eq!(display.next, None);
+    }
+
+    #[test]
+    fn example_timeline_loads() {
+        let scheduler = Scheduler::load("../resources/timeline.json").unwrap();
+        assert_eq!(scheduler.upcoming().unwrap().label(), "Team select");
+    }
+
+    #[test]
+    fn load_checks_every_cue() {
+        let dir = TempDir::new("scheduler");
+        let path = dir.join("timeline.json");
+        let path = path.to_str().unwrap();
+        for cue in [
+            r#"{"at_secs": 1e20, "action": {"do": "start"}}"#,
+            r#"{"at_secs": -1, "action": {"do": "start"}}"#,
+            r#"{"at_secs": 5, "action": {"do": "announce", "text": "Hi", "for_secs": 1e20}}"#,
+            r#"{"at_secs": 5, "action": {"do": "threshold", "profile": {"fixed": 1.5}}}"#,
+            r#"{"at_secs": 5, "action": {"do": "threshold", "profile": {"drift": {"min": 0.9, "max": 0.3}}}}"#,
+        ] {
+            let timeline = format!(
+                r#"[{{"at_secs": 0, "action": {{"do": "setup"}}}}, {}]"#,
+                cue
+            );
+            std::fs::write(path, timeline).unwrap();
+            assert!(
+                matches!(
+                    Scheduler::load(path),
+                    Err(SchedulerError::Cue { index: 1, .. })
+                ),
+                "{}",
+                cue
+            );
+        }
+    }
+
+    #[test]
+    fn pausing_holds_the_clock() {
+        let mut scheduler = timeline();
+        let start = Instant::now();
+        assert!(!scheduler.resume(start));
+        scheduler.start(start);
+        scheduler.due(start);
+
+        assert!(scheduler.pause(start + Duration::from_secs(100)));
+        assert!(!scheduler.pause(start + Duration::from_secs(100)));
+        assert!(scheduler.due(start + Duration::from_secs(1000)).is_empty());
+        assert_eq!(
+            scheduler.display(start + Duration::from_secs(1000)).current,
+            Some("Now: team select (paused)".to_string())
+        );
+
+        assert!(scheduler.resume(start + Duration::from_secs(1000)));
+        // 100s before the pause and 199s after it
+        assert!(scheduler.due(start + Duration::from_secs(1199)).is_empty());
+        assert_eq!(scheduler.due(start + Duration::from_secs(1200)).len(), 1);
+        assert_eq!(
+            scheduler.elapsed(start + Duration::from_secs(1200)),
+            Duration::from_secs(300)
+        );
+    }
+}
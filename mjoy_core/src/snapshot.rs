@@ -0,0 +1,147 @@
+use command_server::events::EventHub;
+use command_server::state::{SharedSnapshot, Snapshot};
+use mjoy_gui::gui::feedback_info::{FeedbackInfo, PressState};
+use serde_json::{json, Value};
+
+/// Everything the read-only state endpoints show, borrowed from main's loop.
+pub struct SnapshotContext<'a> {
+    pub game_state: &'a crate::GameState,
+    pub threshold: f32,
+    pub aggregation: command_server::AggregationMode,
+    pub prompt: &'a Option<String>,
+    pub teams: &'a crate::TeamLock,
+    pub presence: &'a crate::presence::Presence,
+    pub minimal_path_lookup: &'a crate::joypaths::MinimalPathLookup,
+    pub event_path_lookup: &'a crate::joypaths::EventPathLookup,
+    pub gilrs: &'a gilrs::Gilrs,
+    pub schedule: &'a crate::scheduler::Scheduler,
+}
+
+impl SnapshotContext<'_> {
+    fn teams(&self) -> Value {
+        let teams: Vec<Value> = self
+            .teams
+            .teams
+            .iter()
+            .map(|team| {
+                let players: Vec<Value> = team
+                    .players
+                    .iter()
+                    .map(|player| {
+                        json!({
+                            "name": player,
+                            "connected": self.presence.is_connected(player),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "name": team.name,
+                    "out_index": team.out_index,
+                    "players": players,
+                })
+            })
+            .collect();
+        Value::Array(teams)
+    }
+
+    fn bindings(&self) -> Value {
+        let mut named_paths: Vec<&crate::joypaths::NamedPath> =
+            self.minimal_path_lookup.0.values().collect();
+        named_paths.sort_by(|a, b| a.identity.cmp(&b.identity));
+        serde_json::to_value(named_paths).unwrap_or_default()
+    }
+
+    fn devices(&self) -> Value {
+        let devices: Vec<Value> = self
+            .gilrs
+            .gamepads()
+            .map(|(_id, gamepad)| {
+                // No identity means discovery skipped it or the device filter dropped it
+                let identity = self.event_path_lookup.0.get(gamepad.devpath());
+                let player = identity
+                    .and_then(|identity| self.minimal_path_lookup.0.get(identity))
+                    .and_then(|np| np.common_name.as_ref());
+                json!({
+                    "name": gamepad.name(),
+                    "devpath": gamepad.devpath(),
+                    "uuid": crate::calibration::uuid_key(gamepad.uuid()),
+                    "identity": identity,
+                    "player": player,
+                })
+            })
+            .collect();
+        Value::Array(devices)
+    }
+
+    /// Buttons held on each team's pad, while a game is on.
+    fn presses(feedback: &FeedbackInfo) -> Value {
+        let teams: Vec<Value> = feedback
+            .teams
+            .iter()
+            .map(|team| {
+                let pressed: Vec<&str> = team
+                    .feedback
+                    .0
+                    .iter()
+                    .filter(|press| press.state == PressState::Pressed)
+                    .map(|press| press.button.as_str())
+                    .collect();
+                json!({ "team": team.team_name, "pressed": pressed })
+            })
+            .collect();
+        Value::Array(teams)
+    }
+
+    /// Offers every live stream to the hub, which sends whatever changed.
+    pub fn offer_events(&self, hub: &EventHub, feedback: Option<&FeedbackInfo>) {
+        let binding = if *self.game_state == crate::GameState::Binding {
+            json!({
+                "prompt": self.prompt,
+                "unbound_pads": crate::bindings::unbound_pads(
+                    self.gilrs,
+                    self.event_path_lookup,
+                    self.minimal_path_lookup,
+                ),
+            })
+        } else {
+            Value::Null
+        };
+        let presses = match feedback {
+            Some(feedback) if *self.game_state == crate::GameState::GameActive => {
+                Self::presses(feedback)
+            }
+            _ => Value::Null,
+        };
+        hub.offer(
+            std::time::Instant::now(),
+            vec![
+                (
+                    "game_state",
+                    serde_json::to_value(self.game_state).unwrap_or_default(),
+                ),
+                ("roster", self.teams()),
+                ("binding", binding),
+                ("threshold", json!(self.threshold)),
+                ("presses", presses),
+                ("schedule", self.schedule.to_json(std::time::Instant::now())),
+            ],
+        );
+    }
+
+    pub fn publish(&self, shared: &SharedSnapshot) {
+        let snapshot = Snapshot {
+            game_state: serde_json::to_value(self.game_state).unwrap_or_default(),
+            threshold: self.threshold,
+            aggregation: serde_json::to_value(self.aggregation).unwrap_or_default(),
+            prompt: self.prompt.clone(),
+            teams: self.teams(),
+            bindings: self.bindings(),
+            devices: self.devices(),
+            schedule: self.schedule.to_json(std::time::Instant::now()),
+        };
+        match shared.write() {
+            Ok(mut published) => *published = snapshot,
+            Err(_) => tracing::error!("State snapshot lock is poisoned"),
+        }
+    }
+}
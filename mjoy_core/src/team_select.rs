@@ -1,3 +1,100 @@
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TeamEditError {
+    #[error("no team named {0}")]
+    NoSuchTeam(String),
+    #[error("a team is already named {0}")]
+    NameTaken(String),
+    #[error("{0:?} can't be a team name")]
+    InvalidName(String),
+    #[error("there can be 1 to {} teams, not {0}", DEFAULT_TEAM_NAMES.len())]
+    BadCount(usize),
+}
+
+/// Names for new teams, in order. Team select moves between at most this many.
+const DEFAULT_TEAM_NAMES: [&str; 4] = [
+    "Elemental Moose",
+    "Lucky Bulldogs",
+    "Orange Dragons",
+    "Stubborn TrashPandas",
+];
+
+impl From<TeamEditError> for command_server::Refusal {
+    fn from(e: TeamEditError) -> Self {
+        match e {
+            TeamEditError::NoSuchTeam(_) => command_server::Refusal::NotFound(e.to_string()),
+            TeamEditError::NameTaken(_) => command_server::Refusal::Conflict(e.to_string()),
+            TeamEditError::InvalidName(_) | TeamEditError::BadCount(_) => {
+                command_server::Refusal::Invalid(e.to_string())
+            }
+        }
+    }
+}
+
+/// Puts `player` on the team named `team`, taking them off any other. Works for
+/// players without a pad too, they just show as disconnected.
+pub fn move_player(
+    teams: &mut crate::TeamLock,
+    player: &str,
+    team: &str,
+) -> Result<(), TeamEditError> {
+    let Some(target) = teams.teams.iter().position(|t| t.name == team) else {
+        return Err(TeamEditError::NoSuchTeam(team.to_string()));
+    };
+    for t in teams.teams.iter_mut() {
+        t.players.retain(|p| p != player);
+    }
+    teams.teams[target].players.push(player.to_string());
+    Ok(())
+}
+
+/// Drops teams from the end or adds new ones until there are `count`. Players on a
+/// dropped team are left without one.
+pub fn resize(teams: &mut crate::TeamLock, count: usize) -> Result<(), TeamEditError> {
+    if !(1..=DEFAULT_TEAM_NAMES.len()).contains(&count) {
+        return Err(TeamEditError::BadCount(count));
+    }
+    teams.teams.truncate(count);
+    while teams.teams.len() < count {
+        // Renamed teams may have freed a default name or taken one
+        let taken = |name: &str| teams.teams.iter().any(|t| t.name == name);
+        let name = DEFAULT_TEAM_NAMES
+            .iter()
+            .find(|name| !taken(name))
+            .expect("fewer teams than default names");
+        let out_index = (0..)
+            .find(|i| !teams.teams.iter().any(|t| t.out_index == *i))
+            .expect("a free output");
+        teams.teams.push(crate::Team {
+            name: name.to_string(),
+            players: vec![],
+            out_index,
+        });
+    }
+    Ok(())
+}
+
+/// The name also picks the team's logo from the images folder, so it has to be usable
+/// as a file name there.
+pub fn rename_team(
+    teams: &mut crate::TeamLock,
+    team: &str,
+    name: &str,
+) -> Result<(), TeamEditError> {
+    if name.trim().is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(TeamEditError::InvalidName(name.to_string()));
+    }
+    if team != name && teams.teams.iter().any(|t| t.name == name) {
+        return Err(TeamEditError::NameTaken(name.to_string()));
+    }
+    match teams.teams.iter_mut().find(|t| t.name == team) {
+        Some(t) => {
+            t.name = name.to_string();
+            Ok(())
+        }
+        None => Err(TeamEditError::NoSuchTeam(team.to_string())),
+    }
+}
+
 pub fn mutate_team_selection(
     teams: &mut crate::TeamLock,
     epl: &crate::joypaths::EventPathLookup,
@@ -105,3 +202,81 @@ pub fn mutate_team_selection(
     }
     changed
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Team, TeamLock};
+
+    fn teams() -> TeamLock {
+        TeamLock {
+            teams: vec![
+                Team {
+                    name: "Elemental Moose".to_string(),
+                    players: vec!["Egg".to_string(), "Toast".to_string()],
+                    out_index: 0,
+                },
+                Team {
+                    name: "Lucky Bulldogs".to_string(),
+                    players: vec![],
+                    out_index: 1,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn moves_between_teams() {
+        let mut teams = teams();
+        move_player(&mut teams, "Egg", "Lucky Bulldogs").unwrap();
+        assert_eq!(teams.teams[0].players, vec!["Toast"]);
+        assert_eq!(teams.teams[1].players, vec!["Egg"]);
+
+        assert_eq!(
+            move_player(&mut teams, "Toast", "Orange Dragons"),
+            Err(TeamEditError::NoSuchTeam("Orange Dragons".to_string()))
+        );
+        assert_eq!(teams.teams[0].players, vec!["Toast"]);
+    }
+
+    #[test]
+    fn resizes_with_fresh_names_and_outputs() {
+        let mut teams = teams();
+        teams.teams[1].name = "Orange Dragons".to_string();
+        resize(&mut teams, 3).unwrap();
+        let names: Vec<&str> = teams.teams.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["Elemental Moose", "Orange Dragons", "Lucky Bulldogs"]
+        );
+        assert_eq!(teams.teams[2].out_index, 2);
+
+        resize(&mut teams, 1).unwrap();
+        assert_eq!(teams.teams.len(), 1);
+        assert_eq!(teams.teams[0].players, vec!["Egg", "Toast"]);
+        assert_eq!(resize(&mut teams, 0), Err(TeamEditError::BadCount(0)));
+        assert_eq!(resize(&mut teams, 5), Err(TeamEditError::BadCount(5)));
+    }
+
+    #[test]
+    fn renames_keep_names_unique() {
+        let mut teams = teams();
+        rename_team(&mut teams, "Lucky Bulldogs", "Orange Dragons").unwrap();
+        assert_eq!(teams.teams[1].name, "Orange Dragons");
+        assert_eq!(
+            rename_team(&mut teams, "Orange Dragons", "Elemental Moose"),
+            Err(TeamEditError::NameTaken("Elemental Moose".to_string()))
+        );
+        assert_eq!(
+            rename_team(&mut teams, "Lucky Bulldogs", "Pigeons"),
+            Err(TeamEditError::NoSuchTeam("Lucky Bulldogs".to_string()))
+        );
+        for name in ["", " ", "../Pigeons", "Pigeons/Doves", ".."] {
+            assert_eq!(
+                rename_team(&mut teams, "Orange Dragons", name),
+                Err(TeamEditError::InvalidName(name.to_string()))
+            );
+        }
+        assert_eq!(teams.teams[1].name, "Orange Dragons");
+    }
+}
@@ -0,0 +1,54 @@
+//! Fixtures shared by the unit tests.
+
+use crate::joypaths::{MinimalPathLookup, NamedPath};
+use std::path::{Path, PathBuf};
+
+/// An empty directory of its own under the system temp dir, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` only needs to be unique among this crate's tests.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("mjoy_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A pad keyed by its port path, as `MinimalPathLookup` holds it.
+pub fn pad(identity: &str, name: Option<&str>) -> (String, NamedPath) {
+    (
+        identity.to_string(),
+        NamedPath {
+            full_path: String::new(),
+            minimal_path: identity.to_string(),
+            root_event_path: String::new(),
+            common_name: name.map(|n| n.to_string()),
+            identity: identity.to_string(),
+        },
+    )
+}
+
+pub fn mpl(bound: &[(&str, Option<&str>)]) -> MinimalPathLookup {
+    MinimalPathLookup(
+        bound
+            .iter()
+            .map(|(identity, name)| pad(identity, *name))
+            .collect(),
+    )
+}
@@ -6,6 +6,7 @@ pub struct FeedbackInfo {
 pub struct Player {
     pub player_name: String,
     pub feedback: Presses,
+    pub connected: bool,
 }
 #[derive(Clone, PartialEq, Eq)]
 pub enum PressState {
@@ -26,3 +27,12 @@ pub struct Team {
     pub players: Vec<Player>,
     pub feedback: Presses,
 }
+
+/// Where a scripted session is, drawn along the bottom of the screen.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CueDisplay {
+    pub current: Option<String>,
+    pub next: Option<String>,
+    /// Shown large until the next cue
+    pub announcement: Option<String>,
+}
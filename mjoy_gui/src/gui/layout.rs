@@ -0,0 +1,282 @@
+//! Where everything on the scoreboard goes, in window pixels with the origin at the
+//! top left and y going down. Plain arithmetic, so it can be checked without a window.
+
+use super::{WidthHeight, XRATIO_DENOM, YRATIO_DENOM};
+
+// Sizes are for a 1920x1080 window and scale with it. Teams stay in a column down
+// each side, the emulator is moved to the space between them.
+const COLUMN_WIDTH: f32 = 300f32;
+// Along the bottom of the left column, for the schedule's cues
+const FOOTER_HEIGHT: f32 = 110f32;
+const MARGIN: f32 = 10f32;
+const LOGO_SIZE: f32 = 200f32;
+const TITLE_SIZE: f32 = 42f32;
+const PLAYER_INDENT: f32 = 25f32;
+const PLAYER_SIZE: f32 = 35f32;
+const PLAYER_SPACING: f32 = 57f32;
+// Rows shorter than this shrink everything in them
+const FULL_SIZE_ROW: f32 = 480f32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    pub fn right(&self) -> f32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> f32 {
+        self.y + self.height
+    }
+
+    pub fn center(&self) -> Point {
+        Point {
+            x: self.x + self.width / 2f32,
+            y: self.y + self.height / 2f32,
+        }
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.right() <= self.right()
+            && other.bottom() <= self.bottom()
+    }
+
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        self.x < other.right()
+            && other.x < self.right()
+            && self.y < other.bottom()
+            && other.y < self.bottom()
+    }
+}
+
+/// One line of text, `origin` being its top left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextLine {
+    pub origin: Point,
+    pub size: f32,
+}
+
+impl TextLine {
+    /// The `index`th pressed button, in a row of smaller letters under this line.
+    pub fn button(&self, index: usize) -> TextLine {
+        TextLine {
+            origin: Point {
+                x: self.origin.x + (index as f32 + 0.7f32) * self.size * 0.45f32,
+                y: self.origin.y + self.size * 0.75f32,
+            },
+            size: self.size * 0.9f32,
+        }
+    }
+
+    /// Where the button row below the line ends.
+    fn bottom(&self) -> f32 {
+        let button = self.button(0);
+        button.origin.y + button.size
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TeamLayout {
+    /// Everything for the team stays in here
+    pub cell: Rect,
+    pub logo: Rect,
+    pub title: TextLine,
+    /// The player list, names squeeze closer together when there are many
+    pub players: Rect,
+    player_size: f32,
+    player_spacing: f32,
+}
+
+impl TeamLayout {
+    /// The `index`th of `count` players.
+    pub fn player(&self, index: usize, count: usize) -> TextLine {
+        let spacing = self
+            .player_spacing
+            .min(self.players.height / count.max(1) as f32);
+        TextLine {
+            origin: Point {
+                x: self.players.x,
+                y: self.players.y + index as f32 * spacing,
+            },
+            size: self.player_size.min(spacing * 0.6f32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueLayout {
+    pub area: Rect,
+    pub announcement: TextLine,
+    pub current: TextLine,
+    pub next: TextLine,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub teams: Vec<TeamLayout>,
+    pub cues: CueLayout,
+    /// The name being bound and other prompts, over the top left team
+    pub prompt: TextLine,
+}
+
+impl Layout {
+    /// Teams alternate left and right, top to bottom, so four teams take the corners.
+    pub fn new(teams: usize, window: &WidthHeight) -> Layout {
+        let (width, height) = (window.width as f32, window.height as f32);
+        let (sx, sy) = (width / XRATIO_DENOM, height / YRATIO_DENOM);
+        // Logos stay square and text keeps its shape in odd windows
+        let scale = sx.min(sy);
+
+        let column_width = COLUMN_WIDTH * sx;
+        let footer_height = FOOTER_HEIGHT * sy;
+        let rows = teams.div_ceil(2).max(1);
+        let row_height = (height - footer_height) / rows as f32;
+        let size = scale * (row_height / (FULL_SIZE_ROW * sy)).min(1f32);
+        let margin = MARGIN * size;
+
+        let teams = (0..teams)
+            .map(|i| {
+                let left = i % 2 == 0;
+                let cell = Rect {
+                    x: if left { 0f32 } else { width - column_width },
+                    y: (i / 2) as f32 * row_height,
+                    width: column_width,
+                    height: row_height,
+                };
+                let logo_size = (LOGO_SIZE * size).min(column_width - 2f32 * margin);
+                let logo = Rect {
+                    x: if left {
+                        cell.x + margin
+                    } else {
+                        cell.right() - margin - logo_size
+                    },
+                    y: cell.y + margin,
+                    width: logo_size,
+                    height: logo_size,
+                };
+                let title = TextLine {
+                    origin: Point {
+                        x: cell.x + margin,
+                        y: logo.bottom() + margin,
+                    },
+                    size: TITLE_SIZE * size,
+                };
+                let players_top = title.bottom();
+                let players = Rect {
+                    x: cell.x + margin + PLAYER_INDENT * size,
+                    y: players_top,
+                    width: cell.width - 2f32 * margin - PLAYER_INDENT * size,
+                    height: (cell.bottom() - margin - players_top).max(0f32),
+                };
+                TeamLayout {
+                    cell,
+                    logo,
+                    title,
+                    players,
+                    player_size: PLAYER_SIZE * size,
+                    player_spacing: PLAYER_SPACING * size,
+                }
+            })
+            .collect();
+
+        let area = Rect {
+            x: 0f32,
+            y: height - footer_height,
+            width: column_width,
+            height: footer_height,
+        };
+        let line = |y: f32, text_size: f32| TextLine {
+            origin: Point {
+                x: area.x + MARGIN * scale,
+                y: area.y + y * scale,
+            },
+            size: text_size * scale,
+        };
+        let cues = CueLayout {
+            area,
+            announcement: line(8f32, 36f32),
+            current: line(50f32, 24f32),
+            next: line(78f32, 20f32),
+        };
+
+        let prompt = TextLine {
+            origin: Point {
+                x: MARGIN * scale,
+                y: MARGIN * scale,
+            },
+            size: 30f32 * scale,
+        };
+
+        Layout {
+            teams,
+            cues,
+            prompt,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn four_teams_take_the_corners() {
+        let layout = Layout::new(4, &WidthHeight::new(1920, 1080));
+        let centers: Vec<Point> = layout.teams.iter().map(|t| t.logo.center()).collect();
+        assert!(centers[0].x < 960f32 && centers[0].y < 540f32);
+        assert!(centers[1].x > 960f32 && centers[1].y < 540f32);
+        assert!(centers[2].x < 960f32 && centers[2].y > 540f32);
+        assert!(centers[3].x > 960f32 && centers[3].y > 540f32);
+        assert_eq!(layout.teams[0].logo.width, LOGO_SIZE);
+        // The emulator goes in between
+        assert!(layout.teams.iter().all(|t| t.cell.width == COLUMN_WIDTH));
+    }
+
+    #[test]
+    fn nothing_overlaps_or_leaves_the_window() {
+        for window in [
+            WidthHeight::new(1920, 1080),
+            WidthHeight::new(1280, 720),
+            WidthHeight::new(1024, 1024),
+            WidthHeight::new(3840, 1600),
+        ] {
+            let screen = Rect {
+                x: 0f32,
+                y: 0f32,
+                width: window.width as f32,
+                height: window.height as f32,
+            };
+            for count in 1..=8 {
+                let layout = Layout::new(count, &window);
+                assert_eq!(layout.teams.len(), count);
+                assert!(screen.contains(&layout.cues.area));
+                for (i, team) in layout.teams.iter().enumerate() {
+                    assert!(screen.contains(&team.cell), "{} teams, team {}", count, i);
+                    assert!(team.cell.contains(&team.logo));
+                    assert!(team.cell.contains(&team.players));
+                    assert!(team.title.origin.y >= team.logo.bottom());
+                    assert!(team.players.y > team.title.origin.y);
+                    assert!(!team.cell.overlaps(&layout.cues.area));
+                    let last = team.player(4, 5);
+                    assert!(last.button(0).origin.y + last.size <= team.cell.bottom());
+                    for other in layout.teams[i + 1..].iter() {
+                        assert!(!team.cell.overlaps(&other.cell));
+                    }
+                }
+            }
+        }
+    }
+}
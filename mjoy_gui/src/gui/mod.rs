@@ -3,8 +3,12 @@ use kiss3d::window::Window;
 
 use crate::manipulate_emulator;
 
-use self::feedback_info::FeedbackInfo;
+use self::feedback_info::{CueDisplay, FeedbackInfo};
+use self::layout::Layout;
 pub mod feedback_info;
+pub mod layout;
+pub mod offscreen;
+mod scene;
 mod team_color;
 
 struct Hz(f32);
@@ -22,11 +26,12 @@ pub struct Ui {
     width_height: WidthHeight,
     teams: Vec<String>,
     logos: Vec<kiss3d::scene::PlanarSceneNode>,
-    logos_locations: Vec<kiss3d::nalgebra::Translation2<f32>>,
+    layout: Layout,
     font: std::rc::Rc<kiss3d::text::Font>,
     colors: team_color::ColoredTeams,
     did_gui_off: bool,
     did_gui_on: bool,
+    cues: CueDisplay,
 }
 
 pub struct WidthHeight {
@@ -41,6 +46,8 @@ impl WidthHeight {
 
 const XRATIO_DENOM: f32 = 1920f32;
 const YRATIO_DENOM: f32 = 1080f32;
+// `draw_text` works in a space twice the size of the window
+const TEXT_SPACE_SCALE: f32 = 2f32;
 
 pub struct RatioXY<'a> {
     x: f32,
@@ -59,79 +66,114 @@ impl<'a> RatioXY<'a> {
     }
 }
 
-enum SubtextInfo {
-    Myself,
-    Button(i32),
-}
-
-struct DrawPlayerInfo {
-    player_index: usize,
-}
-
-enum TeamOrPlayer {
-    Team,
-    Player(DrawPlayerInfo),
-}
-
-struct DrawTextInfo<'a> {
-    team_index: usize,
-    team_or_player: TeamOrPlayer,
-    color_index: usize,
-    text: &'a str,
-    sub: SubtextInfo,
-    color_override: bool,
-}
-
-const TEXTURE_SIZE: f32 = 220f32;
-
 impl Ui {
     pub fn new(teams: &[String], width_height: WidthHeight) -> Ui {
         let mut window =
             Window::new_with_size("Cool project", width_height.width, width_height.height);
-        window.set_background_color(0.1, 0.1, 0.1);
+        let background = scene::background();
+        window.set_background_color(background.x, background.y, background.z);
         window.set_light(Light::StickToCamera);
 
-        let texture_size = RatioXY::new(TEXTURE_SIZE, TEXTURE_SIZE, &width_height);
-        let texture_position = RatioXY::new(845f32, 260f32, &width_height);
-        let texture_position_bonus = RatioXY::new(0f32, 150f32, &width_height);
-
-        let mut logos: Vec<_> = Vec::new();
-        let mut trans: Vec<_> = Vec::new();
-        for (i, team) in teams.iter().enumerate() {
-            let mut r = window.add_rectangle(texture_size.x(), texture_size.y());
-            let translate = &kiss3d::nalgebra::Translation2::new(
-                texture_position.x() * if i % 2 == 0 { -1 } else { 1 } as f32
-                    + texture_position_bonus.x(),
-                texture_position.y() * if i < 2 { 1 } else { -1 } as f32
-                    + texture_position_bonus.y(),
-            );
-            r.append_translation(translate);
-            trans.push(translate.to_owned());
-            let path = format!("./resources/images/{}.jpg", team);
-            r.set_texture_from_file(std::path::Path::new(&path), team);
-            logos.push(r);
-        }
-
-        let colors = {
-            let hc = team_color::HintedColors::new();
-            let teams: Vec<team_color::Team> = teams.iter().map(|t| team_color::Team(t)).collect();
-            hc.color_teams(&teams.as_slice())
-        };
+        let layout = Layout::new(teams.len(), &width_height);
+        let colors = Self::color_teams(teams);
+        let logos = Self::add_logos(&mut window, teams, &layout, &colors, &width_height);
 
         let ui = Ui {
             window,
             teams: teams.iter().map(|t| t.to_string()).collect(),
             logos,
-            logos_locations: trans,
+            layout,
             colors,
             font: kiss3d::text::Font::new(std::path::Path::new("./resources/impact.ttf")).unwrap(),
             width_height,
             did_gui_on: false,
             did_gui_off: false,
+            cues: CueDisplay::default(),
         };
         ui
     }
 
+    /// Teams without a readable image get a rectangle of their colour instead.
+    fn add_logos(
+        window: &mut Window,
+        teams: &[String],
+        layout: &Layout,
+        colors: &team_color::ColoredTeams,
+        width_height: &WidthHeight,
+    ) -> Vec<kiss3d::scene::PlanarSceneNode> {
+        let mut logos: Vec<_> = Vec::new();
+        for (team, team_layout) in teams.iter().zip(layout.teams.iter()) {
+            let logo = &team_layout.logo;
+            let mut r = window.add_rectangle(logo.width, logo.height);
+            // Planar scenes have the origin in the middle and y going up
+            let center = logo.center();
+            r.append_translation(&kiss3d::nalgebra::Translation2::new(
+                center.x - width_height.width as f32 / 2f32,
+                width_height.height as f32 / 2f32 - center.y,
+            ));
+            let path = format!("./resources/images/{}.jpg", team);
+            // kiss3d panics on a texture it can't load
+            if image::open(&path).is_ok() {
+                r.set_texture_from_file(std::path::Path::new(&path), team);
+            } else if let Some(colored) = colors.0.iter().find(|c| &c.team == team) {
+                let color = colored.color.0;
+                r.set_color(color.x, color.y, color.z);
+            }
+            logos.push(r);
+        }
+        logos
+    }
+
+    fn color_teams(teams: &[String]) -> team_color::ColoredTeams {
+        let hc = team_color::HintedColors::new();
+        let teams: Vec<team_color::Team> = teams.iter().map(|t| team_color::Team(t)).collect();
+        hc.color_teams(&teams.as_slice())
+    }
+
+    pub fn teams(&self) -> &[String] {
+        &self.teams
+    }
+
+    /// Swaps in a different set of teams, e.g. after loading a preset.
+    pub fn set_teams(&mut self, teams: &[String]) {
+        for logo in self.logos.iter_mut() {
+            self.window.remove_planar_node(logo);
+        }
+        self.layout = Layout::new(teams.len(), &self.width_height);
+        self.colors = Self::color_teams(teams);
+        self.logos = Self::add_logos(
+            &mut self.window,
+            teams,
+            &self.layout,
+            &self.colors,
+            &self.width_height,
+        );
+        self.teams = teams.to_vec();
+    }
+
+    /// What the scheduler is up to, drawn on every render until changed.
+    pub fn set_cues(&mut self, cues: CueDisplay) {
+        self.cues = cues;
+    }
+
+    fn draw_label(
+        window: &mut Window,
+        font: &std::rc::Rc<kiss3d::text::Font>,
+        label: &scene::Label,
+    ) {
+        let line = &label.line;
+        window.draw_text(
+            label.text,
+            &kiss3d::nalgebra::Point2::new(
+                line.origin.x * TEXT_SPACE_SCALE,
+                line.origin.y * TEXT_SPACE_SCALE,
+            ),
+            line.size * TEXT_SPACE_SCALE,
+            font,
+            &label.color,
+        );
+    }
+
     pub fn render(
         &mut self,
         feedback: &FeedbackInfo,
@@ -139,13 +181,8 @@ impl Ui {
         bonus_name: Option<String>,
     ) {
         if let Some(name) = bonus_name {
-            self.window.draw_text(
-                &name,
-                &kiss3d::nalgebra::Point2::new(20.0, 20.0),
-                60.0,
-                &self.font,
-                &kiss3d::nalgebra::Point3::new(1.0, 1.0, 1.0),
-            );
+            let label = scene::prompt_label(&name, &self.layout);
+            Self::draw_label(&mut self.window, &self.font, &label);
         }
 
         if show_logos && !self.did_gui_on {
@@ -165,130 +202,16 @@ impl Ui {
             .for_each(|l| l.set_visible(show_logos));
 
         for (i, team) in feedback.teams.iter().enumerate() {
-            let color_idx = self
-                .colors
-                .0
-                .iter()
-                .position(|c| c.team == team.team_name)
-                .unwrap();
             assert!(self.teams[i] == team.team_name);
-
-            let mut draw_text_info = DrawTextInfo {
-                team_index: i,
-                team_or_player: TeamOrPlayer::Team,
-                color_index: color_idx,
-                text: &team.team_name,
-                sub: SubtextInfo::Myself,
-                color_override: !show_logos,
-            };
-            if show_logos {
-                self.draw_text(&draw_text_info);
-                for (i, fb) in team.feedback.0.iter().enumerate() {
-                    if fb.state == feedback_info::PressState::Unpressed {
-                        continue;
-                    }
-
-                    draw_text_info.text = &fb.button;
-                    draw_text_info.sub = SubtextInfo::Button(i as i32);
-                    self.draw_text(&draw_text_info);
-                }
-            }
-
-            for (i, player) in team.players.iter().enumerate() {
-                draw_text_info.team_or_player =
-                    TeamOrPlayer::Player(DrawPlayerInfo { player_index: i });
-                draw_text_info.text = &player.player_name;
-                draw_text_info.sub = SubtextInfo::Myself;
-                self.draw_text(&draw_text_info);
-                for (i, fb) in player.feedback.0.iter().enumerate() {
-                    if fb.state == feedback_info::PressState::Unpressed {
-                        continue;
-                    }
-
-                    draw_text_info.text = &fb.button;
-                    draw_text_info.sub = SubtextInfo::Button(i as i32);
-                    self.draw_text(&draw_text_info);
-                }
-            }
         }
-        self.window.render();
-    }
-
-    fn draw_text(&mut self, info: &DrawTextInfo) {
-        use TeamOrPlayer::*;
-
-        let xpos = self.width_height.width as f32
-            + (-250f32 * self.width_height.width as f32 / XRATIO_DENOM)
-            + match info.team_or_player {
-                Team => 0f32,
-                Player(_) => (50f32 * self.width_height.width as f32) / XRATIO_DENOM,
-            }
-            + (self.logos_locations[info.team_index].x)
-                * (1.95f32
-                    + match info.team_or_player {
-                        Team => 0f32,
-                        Player(_) => 0f32,
-                    })
-            + match info.sub {
-                SubtextInfo::Myself => 0f32,
-                SubtextInfo::Button(i) => i as f32 + 0.7f32,
-            } * (35f32 * self.width_height.width as f32)
-                / XRATIO_DENOM;
-
-        let ypos = self.width_height.height as f32
-            + (158f32 * self.width_height.height as f32) / YRATIO_DENOM
-            + (self.logos_locations[info.team_index].y) * -2f32
-            + (63f32
-                * match info.team_or_player {
-                    Team => 0 as f32,
-                    Player(DrawPlayerInfo { player_index, .. }) => {
-                        (player_index as f32 * 1.8 as f32) + 1.9 as f32
-                    }
-                }
-                * self.width_height.height as f32)
-                / YRATIO_DENOM
-            + match info.sub {
-                SubtextInfo::Myself => 0,
-                SubtextInfo::Button(_) => 1,
-            } as f32
-                * (60f32 * self.width_height.height as f32)
-                / YRATIO_DENOM;
-
-        let size = (match info.team_or_player {
-            Team => 85,
-            Player(_) => 70,
-        } as f32
-            * match info.sub {
-                SubtextInfo::Myself => 1f32,
-                SubtextInfo::Button(_) => 0.9f32,
-            }
-            * self.width_height.width as f32)
-            / XRATIO_DENOM;
-
-        let color = self.colors.0[info.color_index].color.0;
-        let color = color
-            * match info.sub {
-                SubtextInfo::Myself => 1f32,
-                SubtextInfo::Button(_) => 0.9f32,
-            };
-        let color = color
-            * match info.team_or_player {
-                Team => 1f32,
-                Player(_) => 0.9f32,
-            };
-        let mut color = color;
-
-        if info.color_override {
-            color = kiss3d::nalgebra::Vector3::new(1f32, 1f32, 1f32).into();
+        let labels = scene::team_labels(feedback, &self.layout, &self.colors, show_logos);
+        for label in labels
+            .iter()
+            .chain(scene::cue_labels(&self.cues, &self.layout).iter())
+        {
+            Self::draw_label(&mut self.window, &self.font, label);
         }
-
-        self.window.draw_text(
-            info.text,
-            &kiss3d::nalgebra::Point2::new(xpos, ypos),
-            size,
-            &self.font,
-            &color,
-        );
+        self.window.render();
     }
 }
 
@@ -0,0 +1,291 @@
+//! Draws the scoreboard into an image instead of a window, for snapshot tests and
+//! thumbnails on machines without a display or GPU.
+
+use super::feedback_info::{CueDisplay, FeedbackInfo};
+use super::layout::Layout;
+use super::scene::{self, Label};
+use super::{team_color, Ui, WidthHeight};
+use image::{Rgba, RgbaImage};
+use kiss3d::nalgebra::Point3;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OffscreenError {
+    #[error("failed to read font {path}: {source}")]
+    ReadFont {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{0} is not a usable font")]
+    BadFont(String),
+    #[error("failed to save {path}: {source}")]
+    Save {
+        path: String,
+        source: image::ImageError,
+    },
+}
+
+fn rgba(color: &Point3<f32>) -> Rgba<u8> {
+    let channel = |c: f32| (c.clamp(0f32, 1f32) * 255f32).round() as u8;
+    Rgba([channel(color.x), channel(color.y), channel(color.z), 255])
+}
+
+/// Mixes `color` into `pixel` by `coverage`, 0 leaving it alone and 1 replacing it.
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>, coverage: f32) {
+    for c in 0..3 {
+        let under = pixel.0[c] as f32;
+        pixel.0[c] = (under + (color.0[c] as f32 - under) * coverage).round() as u8;
+    }
+}
+
+/// Same teams, layout and colours as `Ui`, drawn in software.
+pub struct Offscreen {
+    width_height: WidthHeight,
+    teams: Vec<String>,
+    layout: Layout,
+    colors: team_color::ColoredTeams,
+    font: rusttype::Font<'static>,
+    /// Already scaled to the logo rectangles, None for teams without an image
+    logos: Vec<Option<RgbaImage>>,
+}
+
+impl Offscreen {
+    /// `resources` holds impact.ttf and the images folder, "./resources" for the game.
+    pub fn new(
+        teams: &[String],
+        width_height: WidthHeight,
+        resources: &Path,
+    ) -> Result<Offscreen, OffscreenError> {
+        let font_path = resources.join("impact.ttf");
+        let font_bytes = std::fs::read(&font_path).map_err(|source| OffscreenError::ReadFont {
+            path: font_path.display().to_string(),
+            source,
+        })?;
+        let font = rusttype::Font::try_from_vec(font_bytes)
+            .ok_or_else(|| OffscreenError::BadFont(font_path.display().to_string()))?;
+
+        let layout = Layout::new(teams.len(), &width_height);
+        let logos = teams
+            .iter()
+            .zip(layout.teams.iter())
+            .map(|(team, team_layout)| {
+                let path = resources.join("images").join(format!("{}.jpg", team));
+                let logo = image::open(path).ok()?.to_rgba8();
+                Some(image::imageops::resize(
+                    &logo,
+                    team_layout.logo.width.round() as u32,
+                    team_layout.logo.height.round() as u32,
+                    image::imageops::FilterType::Triangle,
+                ))
+            })
+            .collect();
+
+        Ok(Offscreen {
+            width_height,
+            teams: teams.to_vec(),
+            layout,
+            colors: Ui::color_teams(teams),
+            font,
+            logos,
+        })
+    }
+
+    pub fn teams(&self) -> &[String] {
+        &self.teams
+    }
+
+    /// What `Ui::render` would show for the same arguments.
+    pub fn render(
+        &self,
+        feedback: &FeedbackInfo,
+        show_logos: bool,
+        bonus_name: Option<&str>,
+        cues: &CueDisplay,
+    ) -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(
+            self.width_height.width,
+            self.width_height.height,
+            rgba(&scene::background()),
+        );
+
+        if show_logos {
+            self.draw_logos(&mut image);
+        }
+        let mut labels = Vec::new();
+        if let Some(name) = bonus_name {
+            labels.push(scene::prompt_label(name, &self.layout));
+        }
+        labels.extend(scene::team_labels(
+            feedback,
+            &self.layout,
+            &self.colors,
+            show_logos,
+        ));
+        labels.extend(scene::cue_labels(cues, &self.layout));
+        for label in labels.iter() {
+            self.draw_label(&mut image, label);
+        }
+        image
+    }
+
+    pub fn save_png(
+        &self,
+        feedback: &FeedbackInfo,
+        show_logos: bool,
+        bonus_name: Option<&str>,
+        cues: &CueDisplay,
+        path: &Path,
+    ) -> Result<(), OffscreenError> {
+        self.render(feedback, show_logos, bonus_name, cues)
+            .save_with_format(path, image::ImageFormat::Png)
+            .map_err(|source| OffscreenError::Save {
+                path: path.display().to_string(),
+                source,
+            })
+    }
+
+    /// Teams without an image get a square of their colour instead.
+    fn draw_logos(&self, image: &mut RgbaImage) {
+        for ((team, team_layout), logo) in self
+            .teams
+            .iter()
+            .zip(self.layout.teams.iter())
+            .zip(self.logos.iter())
+        {
+            let (x, y) = (
+                team_layout.logo.x.round() as u32,
+                team_layout.logo.y.round() as u32,
+            );
+            match logo {
+                Some(logo) => image::imageops::overlay(image, logo, x, y),
+                None => {
+                    let color = self
+                        .colors
+                        .0
+                        .iter()
+                        .find(|c| &c.team == team)
+                        .map_or(Rgba([128, 128, 128, 255]), |c| rgba(&c.color.0));
+                    let square = RgbaImage::from_pixel(
+                        team_layout.logo.width.round() as u32,
+                        team_layout.logo.height.round() as u32,
+                        color,
+                    );
+                    image::imageops::overlay(image, &square, x, y);
+                }
+            }
+        }
+    }
+
+    fn draw_label(&self, image: &mut RgbaImage, label: &Label) {
+        let scale = rusttype::Scale::uniform(label.line.size);
+        let ascent = self.font.v_metrics(scale).ascent;
+        let start = rusttype::point(label.line.origin.x, label.line.origin.y + ascent);
+        let color = rgba(&label.color);
+        let (width, height) = image.dimensions();
+        for glyph in self.font.layout(label.text, scale, start) {
+            let Some(bounds) = glyph.pixel_bounding_box() else {
+                continue;
+            };
+            glyph.draw(|x, y, coverage| {
+                let (x, y) = (bounds.min.x + x as i32, bounds.min.y + y as i32);
+                if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                    blend(image.get_pixel_mut(x as u32, y as u32), color, coverage);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gui::feedback_info::{ButtonPress, PressState, Presses};
+
+    fn presses(pressed: &[&str]) -> Presses {
+        Presses(
+            ["<", ">", "A", "B"]
+                .iter()
+                .map(|button| ButtonPress {
+                    button: button.to_string(),
+                    state: if pressed.contains(button) {
+                        PressState::Pressed
+                    } else {
+                        PressState::Unpressed
+                    },
+                })
+                .collect(),
+        )
+    }
+
+    fn feedback(teams: &[String], pressed: &[&str]) -> FeedbackInfo {
+        FeedbackInfo {
+            teams: teams
+                .iter()
+                .map(|team| crate::gui::feedback_info::Team {
+                    team_name: team.clone(),
+                    players: ["Egg", "Star"]
+                        .iter()
+                        .map(|name| crate::gui::feedback_info::Player {
+                            player_name: name.to_string(),
+                            feedback: presses(pressed),
+                            connected: *name == "Egg",
+                        })
+                        .collect(),
+                    feedback: presses(pressed),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn draws_the_scoreboard() {
+        let teams = vec!["American Bison".to_string(), "Nowhere Nobodies".to_string()];
+        let offscreen = Offscreen::new(
+            &teams,
+            WidthHeight::new(960, 540),
+            Path::new("../resources"),
+        )
+        .unwrap();
+        let layout = Layout::new(2, &WidthHeight::new(960, 540));
+        let cues = CueDisplay::default();
+
+        let idle = offscreen.render(&feedback(&teams, &[]), true, None, &cues);
+        assert_eq!(idle.dimensions(), (960, 540));
+        // The emulator's space stays empty
+        assert_eq!(*idle.get_pixel(480, 270), rgba(&scene::background()));
+        // A team without an image shows its colour
+        let center = layout.teams[1].logo.center();
+        let color = offscreen.colors.0.iter().find(|c| c.team == teams[1]);
+        assert_eq!(
+            *idle.get_pixel(center.x as u32, center.y as u32),
+            rgba(&color.unwrap().color.0)
+        );
+
+        let pressing = offscreen.render(&feedback(&teams, &["A"]), true, None, &cues);
+        assert_ne!(idle, pressing);
+        let hidden = offscreen.render(&feedback(&teams, &[]), false, None, &cues);
+        assert_eq!(
+            *hidden.get_pixel(center.x as u32, center.y as u32),
+            rgba(&scene::background())
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "mjoy_gui_offscreen_test_{}.png",
+            std::process::id()
+        ));
+        offscreen
+            .save_png(
+                &feedback(&teams, &["A"]),
+                true,
+                Some("Bind: Egg"),
+                &cues,
+                &path,
+            )
+            .unwrap();
+        assert_eq!(
+            image::open(&path).unwrap().to_rgba8().dimensions(),
+            (960, 540)
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
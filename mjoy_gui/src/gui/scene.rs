@@ -0,0 +1,108 @@
+//! What text goes where and in which colour, shared by the window and the offscreen
+//! renderer so both draw the same scoreboard.
+
+use super::feedback_info::{CueDisplay, FeedbackInfo, PressState, Presses};
+use super::layout::{Layout, TextLine};
+use super::team_color::ColoredTeams;
+use kiss3d::nalgebra::Point3;
+
+// Everything but the team colours is a shade of grey
+const BACKGROUND: f32 = 0.1;
+const WHITE: f32 = 1.0;
+const GREY: f32 = 0.6;
+// Disconnected players are greyed out until they come back or time out
+const DIMMED: f32 = 0.35;
+
+fn grey(level: f32) -> Point3<f32> {
+    Point3::new(level, level, level)
+}
+
+pub fn background() -> Point3<f32> {
+    grey(BACKGROUND)
+}
+
+pub struct Label<'a> {
+    pub text: &'a str,
+    pub line: TextLine,
+    pub color: Point3<f32>,
+}
+
+/// The letters of everything pressed, a shade darker than the name above them.
+fn pressed<'a>(
+    presses: &'a Presses,
+    line: TextLine,
+    color: Point3<f32>,
+) -> impl Iterator<Item = Label<'a>> {
+    presses
+        .0
+        .iter()
+        .enumerate()
+        .filter(|(_, press)| press.state == PressState::Pressed)
+        .map(move |(i, press)| Label {
+            text: &press.button,
+            line: line.button(i),
+            color,
+        })
+}
+
+/// Team names and their presses only show with the logos. Without them everything
+/// is white, as on the binding and team select screens.
+pub fn team_labels<'a>(
+    feedback: &'a FeedbackInfo,
+    layout: &Layout,
+    colors: &ColoredTeams,
+    show_logos: bool,
+) -> Vec<Label<'a>> {
+    let mut labels = Vec::new();
+    for (team, team_layout) in feedback.teams.iter().zip(layout.teams.iter()) {
+        let color = match colors.0.iter().find(|c| c.team == team.team_name) {
+            Some(colored) if show_logos => colored.color.0,
+            _ => grey(WHITE),
+        };
+        let shade = |color: Point3<f32>| if show_logos { color * 0.9f32 } else { color };
+
+        if show_logos {
+            labels.push(Label {
+                text: &team.team_name,
+                line: team_layout.title,
+                color,
+            });
+            labels.extend(pressed(&team.feedback, team_layout.title, shade(color)));
+        }
+
+        for (i, player) in team.players.iter().enumerate() {
+            let line = team_layout.player(i, team.players.len());
+            let (color, button_color) = if player.connected {
+                (shade(color), shade(shade(color)))
+            } else {
+                (grey(DIMMED), grey(DIMMED))
+            };
+            labels.push(Label {
+                text: &player.player_name,
+                line,
+                color,
+            });
+            labels.extend(pressed(&player.feedback, line, button_color));
+        }
+    }
+    labels
+}
+
+pub fn cue_labels<'a>(cues: &'a CueDisplay, layout: &Layout) -> Vec<Label<'a>> {
+    [
+        (&cues.announcement, layout.cues.announcement, grey(WHITE)),
+        (&cues.current, layout.cues.current, grey(WHITE)),
+        (&cues.next, layout.cues.next, grey(GREY)),
+    ]
+    .into_iter()
+    .filter_map(|(text, line, color)| text.as_deref().map(|text| Label { text, line, color }))
+    .collect()
+}
+
+pub fn prompt_label<'a>(prompt: &'a str, layout: &Layout) -> Label<'a> {
+    Label {
+        text: prompt,
+        line: layout.prompt,
+        color: grey(WHITE),
+    }
+}
@@ -0,0 +1,260 @@
+use clap::{Parser, Subcommand};
+use command_server::socket::{default_path, SocketReply, SocketRequest};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+
+/// Controls a running mjoy_core through its control socket.
+#[derive(Parser)]
+struct Cli {
+    /// The `socket_path` from mjoy_core's config
+    #[clap(short, long, default_value_t = default_path())]
+    socket: String,
+    /// Print the raw JSON answer instead of a summary
+    #[clap(long)]
+    json: bool,
+    #[clap(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Game state, threshold, teams and schedule
+    Status,
+    /// Bind controllers
+    Setup,
+    /// Go back to team select
+    Teams {
+        /// How many teams, 1 to 4
+        count: u64,
+    },
+    Start,
+    Pause,
+    Resume,
+    /// Put a player on a team, by name or by its number in `status`
+    Move {
+        player: String,
+        team: String,
+    },
+    /// Rename a team, by name or by its number in `status`
+    Rename {
+        team: String,
+        name: String,
+    },
+    /// Pin the threshold, or `drift` for the default drifting range
+    Threshold {
+        value: String,
+        /// Drift between VALUE and this instead of pinning it
+        #[clap(long)]
+        max: Option<f32>,
+    },
+    Shutdown,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CtlError {
+    #[error("can't reach mjoy at {path}: {source}")]
+    Connect {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("lost the connection: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unexpected answer: {0}")]
+    Reply(#[from] serde_json::Error),
+    #[error("bad threshold {0:?}, expected a number or \"drift\"")]
+    Threshold(String),
+}
+
+/// One connection, one request line and one answer line at a time.
+struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Client {
+    fn connect(path: &str) -> Result<Self, CtlError> {
+        let stream = UnixStream::connect(path).map_err(|source| CtlError::Connect {
+            path: path.to_string(),
+            source,
+        })?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Client { stream, reader })
+    }
+
+    fn ask(&mut self, request: &SocketRequest) -> Result<SocketReply, CtlError> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.stream.write_all(line.as_bytes())?;
+        let mut answer = String::new();
+        self.reader.read_line(&mut answer)?;
+        Ok(serde_json::from_str(&answer)?)
+    }
+}
+
+/// `2` means the second team in `status` unless a team is really called "2".
+fn team_name(teams: &Value, team: &str) -> String {
+    let names: Vec<&str> = teams
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|team| team["name"].as_str())
+        .collect();
+    match team.parse::<usize>() {
+        Ok(number) if !names.contains(&team) => names
+            .get(number.wrapping_sub(1))
+            .map_or_else(|| team.to_string(), |name| name.to_string()),
+        _ => team.to_string(),
+    }
+}
+
+fn threshold_body(value: &str, max: Option<f32>) -> Result<Value, CtlError> {
+    if value == "drift" {
+        return Ok(json!({ "threshold": null }));
+    }
+    let value: f32 = value
+        .parse()
+        .map_err(|_| CtlError::Threshold(value.to_string()))?;
+    Ok(match max {
+        Some(max) => json!({ "min": value, "max": max }),
+        None => json!({ "threshold": value }),
+    })
+}
+
+fn request(action: &Action, client: &mut Client) -> Result<SocketRequest, CtlError> {
+    let mut teams =
+        || -> Result<Value, CtlError> { Ok(client.ask(&SocketRequest::get("/state/teams"))?.body) };
+    Ok(match action {
+        Action::Status => SocketRequest::get("/state"),
+        Action::Setup => SocketRequest::post("/setup", Value::Null),
+        Action::Teams { count } => SocketRequest::post("/teams", json!({ "teams": count })),
+        Action::Start => SocketRequest::post("/start", Value::Null),
+        Action::Pause => SocketRequest::post("/pause", Value::Null),
+        Action::Resume => SocketRequest::post("/resume", Value::Null),
+        Action::Move { player, team } => SocketRequest::post(
+            "/team/move",
+            json!({ "player": player, "team": team_name(&teams()?, team) }),
+        ),
+        Action::Rename { team, name } => SocketRequest::post(
+            "/team/rename",
+            json!({ "team": team_name(&teams()?, team), "name": name }),
+        ),
+        Action::Threshold { value, max } => {
+            SocketRequest::post("/threshold", threshold_body(value, *max)?)
+        }
+        Action::Shutdown => SocketRequest::post("/shutdown", Value::Null),
+    })
+}
+
+/// The `/state` document as a few lines of text.
+fn describe_state(state: &Value) -> String {
+    let mut lines = vec![format!(
+        "Game: {} (threshold {:.2}, {} aggregation)",
+        state["game_state"].as_str().unwrap_or("unknown"),
+        state["threshold"].as_f64().unwrap_or_default(),
+        state["aggregation"].as_str().unwrap_or("unknown"),
+    )];
+    if let Some(prompt) = state["prompt"].as_str() {
+        lines.push(format!("Prompt: {}", prompt));
+    }
+    lines.push("Teams:".to_string());
+    for (i, team) in state["teams"].as_array().into_iter().flatten().enumerate() {
+        let players: Vec<String> = team["players"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|player| {
+                let name = player["name"].as_str().unwrap_or("?");
+                match player["connected"].as_bool() {
+                    Some(false) => format!("{} (disconnected)", name),
+                    _ => name.to_string(),
+                }
+            })
+            .collect();
+        lines.push(format!(
+            "  {}. {}: {}",
+            i + 1,
+            team["name"].as_str().unwrap_or("?"),
+            if players.is_empty() {
+                "nobody".to_string()
+            } else {
+                players.join(", ")
+            }
+        ));
+    }
+    let schedule = &state["schedule"];
+    if !schedule.is_null() {
+        lines.push(format!(
+            "Schedule: {} at {}s, now {}, next {}",
+            if schedule["running"].as_bool() == Some(true) {
+                "running"
+            } else {
+                "stopped"
+            },
+            schedule["elapsed_secs"],
+            schedule["current"].as_str().unwrap_or("-"),
+            schedule["next"].as_str().unwrap_or("-"),
+        ));
+    }
+    lines.join("\n")
+}
+
+fn run(cli: &Cli) -> Result<ExitCode, CtlError> {
+    let mut client = Client::connect(&cli.socket)?;
+    let request = request(&cli.action, &mut client)?;
+    let reply = client.ask(&request)?;
+
+    if reply.status != 200 {
+        let message = reply.body["error"].as_str().unwrap_or("refused");
+        eprintln!("Error ({}): {}", reply.status, message);
+        if cli.json {
+            println!("{}", reply.body);
+        }
+        return Ok(ExitCode::FAILURE);
+    }
+    match (&cli.action, &reply.body["game_state"]) {
+        _ if cli.json => println!("{}", reply.body),
+        (Action::Status, _) => println!("{}", describe_state(&reply.body)),
+        (_, Value::String(game_state)) => println!("ok, game state {}", game_state),
+        _ => println!("ok"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn teams_by_number_or_name() {
+        let teams =
+            json!([{ "name": "Elemental Moose" }, { "name": "2" }, { "name": "Orange Dragons" }]);
+        assert_eq!(team_name(&teams, "1"), "Elemental Moose");
+        assert_eq!(team_name(&teams, "2"), "2");
+        assert_eq!(team_name(&teams, "3"), "Orange Dragons");
+        assert_eq!(team_name(&teams, "0"), "0");
+        assert_eq!(team_name(&teams, "Lucky Bulldogs"), "Lucky Bulldogs");
+
+        assert_eq!(
+            threshold_body("0.3", Some(0.9)).unwrap(),
+            json!({ "min": 0.3f32, "max": 0.9f32 })
+        );
+        assert_eq!(
+            threshold_body("drift", None).unwrap(),
+            json!({ "threshold": null })
+        );
+        assert!(threshold_body("lots", None).is_err());
+    }
+}
@@ -76,6 +76,7 @@ fn main() {
                             gui::feedback_info::Player {
                                 player_name: name,
                                 feedback: gui::feedback_info::Presses(myfb),
+                                connected: true,
                             }
                         })
                         .collect();
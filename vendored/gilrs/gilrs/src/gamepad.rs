@@ -541,7 +541,8 @@ impl Gilrs {
         name: O,
     ) -> Result<String, MappingError> {
         if let Some(gamepad) = self.inner.gamepad(gamepad_id) {
-            if gamepad.is_connected() {
+            // Inverted upstream, see CHANGELOG
+            if !gamepad.is_connected() {
                 return Err(MappingError::NotConnected);
             }
 